@@ -1,5 +1,6 @@
 use std::sync::RwLock;
 use std::sync::Arc;
+use std::collections::HashMap;
 use crate::chat;
 use crate::common;
 use colored::Colorize;
@@ -9,32 +10,97 @@ pub struct Client {
     pub state: Arc<RwLock<ClientState>>,
     pub username: String,
     pub password: String,
+    // serial number of this device, the common name of the client certificate under mutual TLS
+    pub device: String,
     pub req: ClientReq,
 }
 
+// 连接开启TLS的服务器时使用的证书，cert和key用于mutual TLS
+#[derive(Clone, Debug, Default)]
+pub struct TlsOptions {
+    pub ca: String,
+    pub cert: Option<String>,
+    pub key: Option<String>,
+    // server name to verify, defaults to the host of the address
+    pub domain: Option<String>,
+}
+
+pub async fn connect(addr: &str, tls: Option<&TlsOptions>)
+    -> Result<chat::chat_client::ChatClient<tonic::transport::Channel>, Box<dyn std::error::Error>> {
+    use tonic::transport::{Certificate, ClientTlsConfig, Endpoint, Identity};
+    let read = |path: &str| std::fs::read(path).map_err(|e| format!("read {}: {}", path, e));
+    let endpoint = match tls {
+        None => Endpoint::from_shared(format!("http://{addr}"))?,
+        Some(tls) => {
+            let mut config = ClientTlsConfig::new().ca_certificate(Certificate::from_pem(read(&tls.ca)?));
+            if let (Some(cert), Some(key)) = (&tls.cert, &tls.key) {
+                config = config.identity(Identity::from_pem(read(cert)?, read(key)?));
+            }
+            if let Some(domain) = &tls.domain {
+                config = config.domain_name(domain);
+            }
+            Endpoint::from_shared(format!("https://{addr}"))?.tls_config(config)?
+        },
+    };
+    Ok(chat::chat_client::ChatClient::new(endpoint.connect().await?))
+}
+
+// 每层回复的缩进
+const INDENT: &str = "    ";
+// /more每次向前翻的信息条数
+const HISTORY_PAGE: u32 = 50;
+
 #[derive(Clone, Default)]
 pub struct ClientReq {
     pub roomname: Option<String>,
     pub room_password: Option<String>,
     pub history_visible: Option<bool>,
     pub send_str: Option<String>,
+    // peer of the direct conversation, roomname is then the conversation's room
+    pub direct_to: Option<String>,
+    // id of the message being replied to
+    pub reply_to: Option<String>,
+    // uploaded file attached to the message
+    pub blob: Option<chat::BlobRef>,
+    // invite code given to join a room
+    pub invite_code: Option<String>,
 }
 
 pub struct ClientState {
     pub channel: chat::chat_client::ChatClient<tonic::transport::Channel>,
     pub lastupdate_time: u64,
     pub cur_roomname: Option<String>,
-    pub msgnum: u32,
+    // largest seq of the messages received in the current room
+    pub last_seq: u64,
+    // smallest seq shown in the current room, 0 when there is nothing older to fetch
+    pub oldest_seq: u64,
+    // id of our last message in the current room, target of /edit and /delete
+    pub last_sent: Option<String>,
+    // map message id to its seq and reply depth in the current room
+    pub threads: HashMap<String, (u64, usize)>,
+    // signup成功后服务器签发的会话令牌
+    pub token: Option<String>,
 }
 
 impl Client {
-    pub async fn join(&self) -> Result<(), Box<dyn std::error::Error>> {
+    // 房间密码错误时返回false
+    pub async fn join(&self) -> Result<bool, Box<dyn std::error::Error>> {
         let request = self.jn_req();
 
-        let mut state = self.state.write().unwrap();
-        let response_wrapper = state.channel.join(tonic::Request::new(request)).await?;
+        let mut channel = self.state.read().unwrap().channel.clone();
+        let response_wrapper = channel.join(self.request(request)).await?;
         let response = response_wrapper.get_ref();
-        state.msgnum += response.messages.len() as u32;
+        if response.code == chat::ResponseCode::RoomPasswordWrong as i32 {
+            return Ok(false);
+        }
+        let mut state = self.state.write().unwrap();
+        if let Some(last) = response.messages.last() {
+            state.last_seq = last.seq;
+        }
+        state.oldest_seq = match response.messages.first() {
+            Some(first) if response.more_history => first.seq,
+            _ => 0,
+        };
         state.cur_roomname = self.req.roomname.clone();
         drop(state);
 
@@ -42,103 +108,565 @@ impl Client {
         if !response.extra_info.is_empty() {
             printlines.push(response.extra_info.clone());
         }
+        if response.more_history {
+            printlines.push(format!("{}", "type /more for older messages".dimmed()));
+        }
         for msg in response.messages.iter() {
-            printlines.push(format!("{}", msg).clone());
+            printlines.push(self.render(msg));
         } 
         
-        if printlines.len() > 0 {
+        if !printlines.is_empty() {
             print!("\r");
             for line in printlines {
                 println!("{line}");
             }
         }
+        Ok(true)
+    }
+
+    // 订阅当前房间，服务器会先补发last_seq之后的信息，再推送新信息和在线状态变化
+    pub async fn subscribe(&self) -> Result<tonic::Streaming<chat::RoomEvent>, Box<dyn std::error::Error>> {
+        let request = self.sb_req();
+        let mut channel = self.state.read().unwrap().channel.clone();
+        let response_wrapper = channel.subscribe(self.request(request)).await?;
+        Ok(response_wrapper.into_inner())
+    }
+
+    // 处理订阅流推送的一个事件
+    pub fn recv(&mut self, event: &chat::RoomEvent) {
+        match &event.event {
+            Some(chat::room_event::Event::Message(msg)) => {
+                self.state.write().unwrap().last_seq = msg.seq;
+                let line = self.render(msg);
+                if msg.author().as_deref() == Some(self.username.as_str()) {
+                    return;
+                }
+                print!("\r");
+                println!("{}", line);
+            },
+            Some(chat::room_event::Event::Presence(change)) => {
+                if change.username == self.username {
+                    return;
+                }
+                let state = format!("{:?}", change.state()).to_lowercase();
+                print!("\r");
+                println!("{}", format!("{} is {}", change.username, state).dimmed());
+            },
+            Some(chat::room_event::Event::Updated(msg)) => {
+                print!("\r");
+                println!("{}", self.render(msg));
+            },
+            Some(chat::room_event::Event::Member(change)) => {
+                let line = match change.role() {
+                    chat::Role::NotMember => format!("{} was kicked by {}", change.username, change.by),
+                    chat::Role::Banned => format!("{} was banned by {}", change.username, change.by),
+                    role => format!("{} is now {} (by {})", change.username, format!("{:?}", role).to_lowercase(), change.by),
+                };
+                print!("\r");
+                println!("{}", line.dimmed());
+            },
+            Some(chat::room_event::Event::Room(change)) => {
+                let Some(room) = &change.room else {
+                    println!("\r{}", format!("room {} was deleted by {}, type exit() to leave", change.roomname, change.by).red());
+                    return;
+                };
+                let line = if room.name != change.roomname {
+                    // 之后的请求都使用新的房间名
+                    self.req.roomname = Some(room.name.clone());
+                    self.state.write().unwrap().cur_roomname = Some(room.name.clone());
+                    format!("room {} was renamed to {} by {}", change.roomname, room.name, change.by)
+                } else if room.manner != change.by {
+                    format!("{} is now the owner (by {})", room.manner, change.by)
+                } else {
+                    format!("room settings were changed by {}: password {}, history {}{}", change.by,
+                        if room.has_password { "required" } else { "not required" },
+                        if room.history_visible { "visible" } else { "hidden" },
+                        if room.invite_only { ", invite only" } else { "" })
+                };
+                print!("\r");
+                println!("{}", line.dimmed());
+            },
+            None => return,
+        }
+        print!("{}: ", self.username.yellow());
+        use std::io::Write;
+        let _ = std::io::stdout().flush();
+    }
+
+    pub async fn send(&self) -> Result<(), Box<dyn std::error::Error>> {
+        let mut channel = self.state.read().unwrap().channel.clone();
+        let sent = if self.req.direct_to.is_some() {
+            let request = self.dm_req(Some(self.sd_req().message.unwrap()));
+            let response = channel.send_direct(self.request(request)).await?.into_inner();
+            response.conversations.into_iter().next().and_then(|c| c.last_message)
+        } else {
+            channel.send(self.request(self.sd_req())).await?.into_inner().messages.pop()
+        };
+        if let Some(sent) = sent {
+            self.state.write().unwrap().last_sent = Some(sent.id);
+        }
         Ok(())
     }
 
-    pub async fn update(&self) -> Result<(), Box<dyn std::error::Error>> {
-        let request = self.hb_req();
-        let mut state = self.state.write().unwrap();
-        let response_wrapper = state.channel.heartbeat(tonic::Request::new(request)).await?;
-        let response = response_wrapper.get_ref();
-        state.msgnum += response.messages.len() as u32;
-        drop(state);
+    // 修改自己在当前房间发的最后一条信息
+    pub async fn edit_last(&self, text: &str) -> Result<(), Box<dyn std::error::Error>> {
+        let (mut channel, id) = self.last_sent()?;
+        let request = chat::EditMessageRequest {
+            client: Some(self.chat_client()),
+            roomname: self.req.roomname.clone().unwrap(),
+            id,
+            bytes: text.as_bytes().to_vec(),
+        };
+        channel.edit_message(self.request(request)).await?;
+        Ok(())
+    }
 
-        let mut printlines = Vec::<String>::new();
-        if !response.extra_info.is_empty() {
-            printlines.push(response.extra_info.clone());
+    // 删除自己在当前房间发的最后一条信息
+    pub async fn delete_last(&self) -> Result<(), Box<dyn std::error::Error>> {
+        let (mut channel, id) = self.last_sent()?;
+        let request = chat::DeleteMessageRequest {
+            client: Some(self.chat_client()),
+            roomname: self.req.roomname.clone().unwrap(),
+            id,
+        };
+        channel.delete_message(self.request(request)).await?;
+        self.state.write().unwrap().last_sent = None;
+        Ok(())
+    }
+
+    // 回复当前房间中序号为seq的信息
+    pub async fn reply(&mut self, seq: u64, text: &str) -> Result<(), Box<dyn std::error::Error>> {
+        let id = self.message_id(seq)?;
+        self.req.reply_to = Some(id);
+        self.req.send_str = Some(text.to_string());
+        let result = self.send().await;
+        self.req.reply_to = None;
+        result
+    }
+
+    // 分块上传文件，再发送引用它的信息
+    pub async fn send_file(&mut self, path: &str) -> Result<(), Box<dyn std::error::Error>> {
+        let content = std::fs::read(path)?;
+        let filename = std::path::Path::new(path).file_name()
+            .and_then(|f| f.to_str())
+            .unwrap_or(path)
+            .to_string();
+        let client = Some(self.chat_client());
+        let size = content.len() as u64;
+        let chunks: Vec<chat::UploadRequest> = content.chunks(common::BLOB_CHUNK).enumerate()
+            .map(|(i, data)| chat::UploadRequest {
+                client: if i == 0 { client.clone() } else { None },
+                filename: if i == 0 { filename.clone() } else { String::new() },
+                size: if i == 0 { size } else { 0 },
+                data: data.to_vec(),
+            })
+            .collect();
+        let mut channel = self.state.read().unwrap().channel.clone();
+        let response = channel.upload(self.request(tokio_stream::iter(chunks))).await?.into_inner();
+        self.req.blob = Some(response.blob.ok_or("server returned no blob")?);
+        self.req.send_str = Some(String::new());
+        let result = self.send().await;
+        self.req.blob = None;
+        result
+    }
+
+    // 对当前房间中的username执行管理操作：kick、ban、unban、promote或demote
+    pub async fn moderate(&self, action: &str, username: &str) -> Result<(), Box<dyn std::error::Error>> {
+        let mut channel = self.state.read().unwrap().channel.clone();
+        let request = self.request(chat::ModerateRequest {
+            client: Some(self.chat_client()),
+            roomname: self.req.roomname.clone().unwrap(),
+            username: username.to_string(),
+        });
+        match action {
+            "kick" => channel.kick(request).await?,
+            "ban" => channel.ban(request).await?,
+            "unban" => channel.unban(request).await?,
+            "promote" => channel.promote(request).await?,
+            "demote" => channel.demote(request).await?,
+            other => return Err(format!("unknown action {}", other).into()),
+        };
+        Ok(())
+    }
+
+    // 修改当前房间settings中给出的设置，password为空表示取消密码
+    pub async fn update_room(&self, settings: chat::UpdateRoomRequest) -> Result<(), Box<dyn std::error::Error>> {
+        let mut channel = self.state.read().unwrap().channel.clone();
+        let request = chat::UpdateRoomRequest {
+            client: Some(self.chat_client()),
+            roomname: self.req.roomname.clone().unwrap(),
+            ..settings
+        };
+        channel.update_room(self.request(request)).await?;
+        Ok(())
+    }
+
+    pub async fn rename_room(&mut self, new_name: &str) -> Result<(), Box<dyn std::error::Error>> {
+        let mut channel = self.state.read().unwrap().channel.clone();
+        let request = chat::RenameRoomRequest {
+            client: Some(self.chat_client()),
+            roomname: self.req.roomname.clone().unwrap(),
+            new_name: new_name.to_string(),
+        };
+        channel.rename_room(self.request(request)).await?;
+        self.req.roomname = Some(new_name.to_string());
+        self.state.write().unwrap().cur_roomname = Some(new_name.to_string());
+        Ok(())
+    }
+
+    pub async fn delete_room(&self) -> Result<(), Box<dyn std::error::Error>> {
+        let mut channel = self.state.read().unwrap().channel.clone();
+        let request = chat::DeleteRoomRequest {
+            client: Some(self.chat_client()),
+            roomname: self.req.roomname.clone().unwrap(),
+        };
+        channel.delete_room(self.request(request)).await?;
+        Ok(())
+    }
+
+    pub async fn transfer(&self, new_owner: &str) -> Result<(), Box<dyn std::error::Error>> {
+        let mut channel = self.state.read().unwrap().channel.clone();
+        let request = chat::TransferOwnershipRequest {
+            client: Some(self.chat_client()),
+            roomname: self.req.roomname.clone().unwrap(),
+            new_owner: new_owner.to_string(),
+        };
+        channel.transfer_ownership(self.request(request)).await?;
+        Ok(())
+    }
+
+    // 创建当前房间的邀请码，可以使用uses次，hours小时后过期
+    pub async fn create_invite(&self, uses: u32, hours: u64) -> Result<(), Box<dyn std::error::Error>> {
+        let mut channel = self.state.read().unwrap().channel.clone();
+        let request = chat::CreateInviteRequest {
+            client: Some(self.chat_client()),
+            roomname: self.req.roomname.clone().unwrap(),
+            uses,
+            ttl_secs: hours * 3600,
+        };
+        let response = channel.create_invite(self.request(request)).await?.into_inner();
+        for invite in response.invites.iter() {
+            println!("\r{}", format!("invite code {}, share it with: accept {} {}",
+                invite.code, self.req.roomname.as_deref().unwrap(), invite.code).cyan());
+            Self::print_invite(invite);
+        }
+        Ok(())
+    }
+
+    pub async fn list_invites(&self) -> Result<(), Box<dyn std::error::Error>> {
+        let mut channel = self.state.read().unwrap().channel.clone();
+        let request = chat::ListInvitesRequest {
+            client: Some(self.chat_client()),
+            roomname: self.req.roomname.clone().unwrap(),
+        };
+        let response = channel.list_invites(self.request(request)).await?.into_inner();
+        if response.invites.is_empty() {
+            println!("\r{}", "no invites".dimmed());
         }
+        for invite in response.invites.iter() {
+            Self::print_invite(invite);
+        }
+        Ok(())
+    }
+
+    pub async fn revoke_invite(&self, code: &str) -> Result<(), Box<dyn std::error::Error>> {
+        let mut channel = self.state.read().unwrap().channel.clone();
+        let request = chat::RevokeInviteRequest {
+            client: Some(self.chat_client()),
+            roomname: self.req.roomname.clone().unwrap(),
+            code: code.to_string(),
+        };
+        channel.revoke_invite(self.request(request)).await?;
+        println!("\r{}", format!("invite {} revoked", code).dimmed());
+        Ok(())
+    }
+
+    fn print_invite(invite: &chat::Invite) {
+        println!("\r\t{} by {}, {} uses left, expires {}", invite.code, invite.by, invite.uses_left,
+            common::human_milli_seconds(invite.expires));
+    }
+
+    // 订阅只补发最新的一页，event之前漏掉的信息用get_history一页页往回取
+    pub async fn catch_up(&self, event: &chat::RoomEvent) -> Result<(), Box<dyn std::error::Error>> {
+        let (mut channel, last_seq) = {
+            let state = self.state.read().unwrap();
+            (state.channel.clone(), state.last_seq)
+        };
+        let mut before_seq = match &event.event {
+            Some(chat::room_event::Event::Message(msg)) if last_seq > 0 && msg.seq > last_seq + 1 => msg.seq,
+            _ => return Ok(()),
+        };
+        let mut missed = vec![];
+        while before_seq > last_seq + 1 {
+            let request = chat::GetHistoryRequest {
+                client: Some(self.chat_client()),
+                roomname: self.req.roomname.clone().unwrap(),
+                before_seq,
+                limit: HISTORY_PAGE,
+            };
+            let response = channel.get_history(self.request(request)).await?.into_inner();
+            let mut page: Vec<chat::Message> = response.messages.into_iter().filter(|m| m.seq > last_seq).collect();
+            before_seq = match page.first() {
+                Some(first) if response.more_history => first.seq,
+                _ => 0,
+            };
+            page.append(&mut missed);
+            missed = page;
+        }
+        print!("\r");
+        for msg in missed.iter().filter(|m| m.author().as_deref() != Some(self.username.as_str())) {
+            println!("{}", self.render(msg));
+        }
+        Ok(())
+    }
+
+    // 显示当前房间中更早的一页信息
+    pub async fn more(&self) -> Result<(), Box<dyn std::error::Error>> {
+        let (mut channel, oldest_seq) = {
+            let state = self.state.read().unwrap();
+            (state.channel.clone(), state.oldest_seq)
+        };
+        if oldest_seq == 0 {
+            return Err("no older messages".into());
+        }
+        let request = chat::GetHistoryRequest {
+            client: Some(self.chat_client()),
+            roomname: self.req.roomname.clone().unwrap(),
+            before_seq: oldest_seq,
+            limit: HISTORY_PAGE,
+        };
+        let response = channel.get_history(self.request(request)).await?.into_inner();
+        self.state.write().unwrap().oldest_seq = match response.messages.first() {
+            Some(first) if response.more_history => first.seq,
+            _ => 0,
+        };
+        print!("\r");
+        println!("{}", format!("--- messages before #{} ---", oldest_seq).dimmed());
         for msg in response.messages.iter() {
-            let msg_username = &msg.client.as_ref().unwrap().user.as_ref().unwrap().name;
-            if *msg_username != self.username {
-                printlines.push(format!("{}", msg).clone());
-            }
-        } 
-        
-        if printlines.len() > 0 {
-            print!("\r");
-            for line in printlines {
-                println!("{line}");
+            println!("{}", self.render(msg));
+        }
+        if !response.more_history {
+            println!("{}", "--- beginning of the room ---".dimmed());
+        }
+        Ok(())
+    }
+
+    // 搜索当前房间，input中from:<user>、since:<YYYY-MM-DD>和until:<YYYY-MM-DD>是过滤条件，其余是查询词
+    pub async fn search(&self, input: &str) -> Result<(), Box<dyn std::error::Error>> {
+        let mut request = chat::SearchRequest {
+            client: Some(self.chat_client()),
+            roomname: self.req.roomname.clone().unwrap(),
+            ..Default::default()
+        };
+        let mut words = vec![];
+        for word in input.split_whitespace() {
+            if let Some(author) = word.strip_prefix("from:") {
+                request.author = Some(author.to_string());
+            } else if let Some(date) = word.strip_prefix("since:") {
+                request.since = day_start(date)?;
+            } else if let Some(date) = word.strip_prefix("until:") {
+                // 包含这一整天
+                request.until = day_start(date)? + 24 * 3600 * 1000 - 1;
+            } else {
+                words.push(word);
             }
-            print!("{}: ", self.username.yellow());
+        }
+        request.query = words.join(" ");
+
+        let mut channel = self.state.read().unwrap().channel.clone();
+        let response_wrapper = channel.search(self.request(request)).await?;
+        let messages = &response_wrapper.get_ref().messages;
+        print!("\r");
+        if messages.is_empty() {
+            println!("{}", "no matching messages".dimmed());
+        }
+        for msg in messages {
+            println!("{} {} {}", common::human_milli_seconds(msg.time).dimmed(), format!("#{}", msg.seq).dimmed(), msg);
+        }
+        Ok(())
+    }
+
+    // 把序号为seq的信息引用的文件保存到path
+    pub async fn save(&self, seq: u64, path: &str) -> Result<(), Box<dyn std::error::Error>> {
+        let id = self.message_id(seq)?;
+        let mut channel = self.state.read().unwrap().channel.clone();
+        let request = chat::DownloadRequest {
+            client: Some(self.chat_client()),
+            roomname: self.req.roomname.clone().unwrap(),
+            id,
+        };
+        let mut stream = channel.download(self.request(request)).await?.into_inner();
+        let mut file = std::fs::File::create(path)?;
+        let mut size = 0;
+        while let Some(chunk) = stream.message().await? {
             use std::io::Write;
-            let _ = std::io::stdout().flush();
+            file.write_all(&chunk.data)?;
+            size += chunk.data.len() as u64;
         }
+        print!("\r");
+        println!("{}", format!("saved {} to {}", common::human_bytes(size), path).dimmed());
+        Ok(())
+    }
 
+    // 显示以序号为seq的信息为根的讨论串
+    pub async fn showthread(&self, seq: u64) -> Result<(), Box<dyn std::error::Error>> {
+        let id = self.message_id(seq)?;
+        let mut channel = self.state.read().unwrap().channel.clone();
+        let request = chat::GetThreadRequest {
+            client: Some(self.chat_client()),
+            roomname: self.req.roomname.clone().unwrap(),
+            id,
+        };
+        let response_wrapper = channel.get_thread(self.request(request)).await?;
+        let mut depths = HashMap::new();
+        print!("\r");
+        for msg in response_wrapper.get_ref().messages.iter() {
+            let depth = depths.get(&msg.reply_to).map(|d| d + 1).unwrap_or(0);
+            depths.insert(msg.id.clone(), depth);
+            println!("{}{} {}", INDENT.repeat(depth), format!("#{}", msg.seq).dimmed(), msg);
+        }
         Ok(())
     }
 
-    pub async fn send(&self) -> Result<(), Box<dyn std::error::Error>> {
+    // 对序号为seq的信息添加(add为true时)或撤销表情
+    pub async fn react(&self, seq: u64, emoji: &str, add: bool) -> Result<(), Box<dyn std::error::Error>> {
+        let id = self.message_id(seq)?;
+        let mut channel = self.state.read().unwrap().channel.clone();
+        let request = self.request(chat::ReactionRequest {
+            client: Some(self.chat_client()),
+            roomname: self.req.roomname.clone().unwrap(),
+            id,
+            emoji: emoji.to_string(),
+        });
+        if add {
+            channel.add_reaction(request).await?;
+        } else {
+            channel.remove_reaction(request).await?;
+        }
+        Ok(())
+    }
+
+    // 当前房间中序号为seq的信息的id
+    fn message_id(&self, seq: u64) -> Result<String, Box<dyn std::error::Error>> {
+        let id = self.state.read().unwrap().threads.iter()
+            .find(|(_, (s, _))| *s == seq)
+            .map(|(id, _)| id.clone())
+            .ok_or_else(|| format!("no message #{} in this room", seq))?;
+        Ok(id)
+    }
+
+    // 记录信息的seq和回复层级，回复缩进显示在被回复的信息之下
+    fn render(&self, msg: &chat::Message) -> String {
         let mut state = self.state.write().unwrap();
-        state.channel.send(tonic::Request::new(self.sd_req())).await?;
+        let parent = state.threads.get(&msg.reply_to).copied();
+        let depth = parent.map(|(_, depth)| depth + 1).unwrap_or(0);
+        state.threads.insert(msg.id.clone(), (msg.seq, depth));
+        let line = format!("{}{} {}", INDENT.repeat(depth), format!("#{}", msg.seq).dimmed(), msg);
+        match parent {
+            Some((seq, _)) => format!("{} {}", line, format!("(re #{})", seq).dimmed()),
+            None if !msg.reply_to.is_empty() => format!("{} {}", line, "(reply)".dimmed()),
+            None => line,
+        }
+    }
+
+    fn last_sent(&self) -> Result<(chat::chat_client::ChatClient<tonic::transport::Channel>, String), Box<dyn std::error::Error>> {
+        let state = self.state.read().unwrap();
+        let id = state.last_sent.clone().ok_or("you have not sent anything in this room")?;
+        Ok((state.channel.clone(), id))
+    }
+
+    // 打开与direct_to的私聊会话，返回会话的房间名
+    pub async fn open_direct(&self) -> Result<String, Box<dyn std::error::Error>> {
+        let mut channel = self.state.read().unwrap().channel.clone();
+        let response_wrapper = channel.send_direct(self.request(self.dm_req(None))).await?;
+        let conversation = response_wrapper.into_inner().conversations.pop()
+            .ok_or("server returned no conversation")?;
+        Ok(conversation.roomname)
+    }
+
+    pub async fn listconversations(&self) -> Result<(), Box<dyn std::error::Error>> {
+        let mut channel = self.state.read().unwrap().channel.clone();
+        let request = chat::ListConversationsRequest {
+            client: Some(self.chat_client()),
+        };
+        let response_wrapper = channel.list_conversations(self.request(request)).await?;
+        for conversation in response_wrapper.get_ref().conversations.iter() {
+            print!("\t{} ({} messages)", conversation.peer.bold(), conversation.message_count);
+            match &conversation.last_message {
+                Some(last) => println!(" {}", last),
+                None => println!(),
+            }
+        }
         Ok(())
     }
 
     pub async fn signup(&self) -> Result<(), Box<dyn std::error::Error>> {
-        let mut state = self.state.write().unwrap();
-        let response_wrapper = state.channel.signup(tonic::Request::new(self.su_req())).await?;
+        let mut channel = self.state.read().unwrap().channel.clone();
+        let response_wrapper = channel.signup(self.request(self.su_req())).await?;
         let response = response_wrapper.get_ref();
         if response.code == chat::ResponseCode::PasswordWrong as i32 {
             return Err(anyhow::anyhow!("Password is wrong").into());
         }
+        self.state.write().unwrap().token = Some(response.token.clone());
+        Ok(())
+    }
+
+    pub async fn logout(&self) -> Result<(), Box<dyn std::error::Error>> {
+        let mut channel = self.state.read().unwrap().channel.clone();
+        channel.logout(self.request(self.lo_req())).await?;
+        self.state.write().unwrap().token = None;
         Ok(())
     }
 
     pub async fn createroom(&self) -> Result<(), Box<dyn std::error::Error>> {
-        let mut state = self.state.write().unwrap();
-        state.channel.createroom(tonic::Request::new(self.cr_req())).await?;
+        let mut channel = self.state.read().unwrap().channel.clone();
+        channel.createroom(self.request(self.cr_req())).await?;
         Ok(())
     }
 
     pub async fn exitroom(&self) -> Result<(), Box<dyn std::error::Error>> {
+        let (mut channel, cur_roomname) = {
+            let state = self.state.read().unwrap();
+            (state.channel.clone(), state.cur_roomname.clone().unwrap())
+        };
+        channel.exitroom(self.request(self.er_req(cur_roomname))).await?;
         let mut state = self.state.write().unwrap();
-        let cur_roomname = state.cur_roomname.clone().unwrap();
-        state.channel.exitroom(tonic::Request::new(self.er_req(cur_roomname))).await?;
         state.lastupdate_time = 0;
         state.cur_roomname = None;
-        state.msgnum = 0;
+        state.last_seq = 0;
+        state.oldest_seq = 0;
+        state.last_sent = None;
+        state.threads.clear();
         Ok(())
     }
 
     pub async fn listrooms(&self) -> Result<(), Box<dyn std::error::Error>> {
-        let mut state = self.state.write().unwrap();
-        let response_wrapper = state.channel.getrooms(tonic::Request::new(self.gr_req())).await?;
+        let mut channel = self.state.read().unwrap().channel.clone();
+        let response_wrapper = channel.getrooms(self.request(self.gr_req())).await?;
         let response = response_wrapper.get_ref();
-        for roominfo in response.roominfos.iter() {
-            print!("\t{} ({}, online: [", &roominfo.name, &roominfo.manner.as_ref().unwrap().username().bold());
+        for roominfo in response.rooms.iter() {
+            print!("\t{} ({}, online: [", &roominfo.name, &roominfo.manner.bold());
             for i in 0..roominfo.online_users.len() {
                 print!("{}", roominfo.online_users[i]);
                 if i != roominfo.online_users.len() - 1 {
                     print!(",");
                 } 
             }
-            println!("])");
+            print!("]");
+            if !roominfo.away_users.is_empty() {
+                print!(", away: [{}]", roominfo.away_users.join(","));
+            }
+            if roominfo.invite_only {
+                print!(", {}", "invite only".dimmed());
+            }
+            println!(")");
         }
         Ok(())
     }
 
     pub async fn listusers(&self) -> Result<(), Box<dyn std::error::Error>> {
-        let mut state = self.state.write().unwrap();
-        let response_wrapper = state.channel.getusers(tonic::Request::new(self.gu_req())).await?;
+        let mut channel = self.state.read().unwrap().channel.clone();
+        let response_wrapper = channel.getusers(self.request(self.gu_req())).await?;
         let response = response_wrapper.get_ref();
         for user in response.users.iter() {
             println!("{}", user.name);
@@ -146,127 +674,140 @@ impl Client {
         Ok(())
     }
 
-    fn hb_req(&self) -> chat::HeartBeatRequest {
-        chat::HeartBeatRequest {
-            client: Some(chat::Client {
-                user: Some(chat::User {
-                    name: self.username.clone(),
-                    password: self.password.clone(),
-                    gender: Some(1),
-                }),
-                device: Some(chat::Device::default()),
+    // 密码只在signup时发送，其余rpc携带会话令牌
+    fn chat_client(&self) -> chat::Client {
+        chat::Client {
+            user: Some(chat::User {
+                name: self.username.clone(),
+                password: String::new(),
+                gender: Some(1),
+            }),
+            device: Some(chat::Device {
+                serial_number: self.device.clone(),
             }),
-            roomname: self.req.roomname.clone().unwrap(),
-            room_password: self.req.room_password.clone(), 
-            lasttime: self.state.read().unwrap().lastupdate_time,
-            msgnum: self.state.read().unwrap().msgnum,
+        }
+    }
+
+    fn request<T>(&self, message: T) -> tonic::Request<T> {
+        let mut request = tonic::Request::new(message);
+        if let Some(token) = &self.state.read().unwrap().token {
+            let value = format!("{}{}", common::BEARER_PREFIX, token);
+            request.metadata_mut().insert(common::AUTH_METADATA, value.parse().unwrap());
+        }
+        request
+    }
+
+    fn lo_req(&self) -> chat::LogoutRequest {
+        chat::LogoutRequest {
+            client: Some(self.chat_client()),
+        }
+    }
+
+    fn sb_req(&self) -> chat::JoinRequest {
+        chat::JoinRequest {
+            after_seq: Some(self.state.read().unwrap().last_seq),
+            ..self.jn_req()
         }
     }
 
     fn jn_req(&self) -> chat::JoinRequest {
         chat::JoinRequest {
-            client: Some(chat::Client {
-                user: Some(chat::User {
-                    name: self.username.clone(),
-                    password: self.password.clone(),
-                    gender: Some(1),
-                }),
-                device: Some(chat::Device::default()),
-            }),
+            client: Some(self.chat_client()),
             roomname: self.req.roomname.clone().unwrap(),
             room_password: self.req.room_password.clone(), 
+            after_seq: None,
+            invite_code: self.req.invite_code.clone(),
         }
     }
 
     fn su_req(&self) -> chat::UserSignupRequest {
         chat::UserSignupRequest {
-            client: Some(chat::Client {
-                user: Some(chat::User {
-                    name: self.username.clone(),
-                    password: self.password.clone(),
-                    gender: Some(1),
-                }),
-                device: Some(chat::Device::default()),
-            }),
+            client: Some(self.chat_client()),
             password: self.password.clone(),
         }
     }
 
     fn gr_req(&self) -> chat::GetRoomsRequest {
         chat::GetRoomsRequest {
-            client: Some(chat::Client {
-                user: Some(chat::User {
-                    name: self.username.clone(),
-                    password: self.password.clone(),
-                    gender: Some(1),
-                }),
-                device: Some(chat::Device::default()),
-            }),
+            client: Some(self.chat_client()),
         }
     }
 
     fn gu_req(&self) -> chat::GetUsersRequest {
         chat::GetUsersRequest {
-            client: Some(chat::Client {
-                user: Some(chat::User {
-                    name: self.username.clone(),
-                    password: self.password.clone(),
-                    gender: Some(1),
-                }),
-                device: Some(chat::Device::default()),
-            }),
+            client: Some(self.chat_client()),
         }
     }
 
     fn cr_req(&self) -> chat::CreateRoomRequest {
         chat::CreateRoomRequest {
-            client: Some(chat::Client {
-                user: Some(chat::User {
-                    name: self.username.clone(),
-                    password: self.password.clone(),
-                    gender: Some(1),
-                }),
-                device: Some(chat::Device::default()),
-            }),
+            client: Some(self.chat_client()),
             roomname: self.req.roomname.clone().unwrap(),
             password: self.req.room_password.clone(), 
-            history_visible: self.req.history_visible.clone().unwrap(),
+            history_visible: self.req.history_visible.unwrap(),
         }
     }
 
     fn er_req(&self, cur_rn: String) -> chat::ExitRoomRequest {
         chat::ExitRoomRequest {
-            client: Some(chat::Client {
-                user: Some(chat::User {
-                    name: self.username.clone(),
-                    password: self.password.clone(),
-                    gender: Some(1),
-                }),
-                device: Some(chat::Device::default()),
-            }),
+            client: Some(self.chat_client()),
             roomname: cur_rn,
         }
     }
 
+    fn dm_req(&self, message: Option<chat::Message>) -> chat::SendDirectRequest {
+        chat::SendDirectRequest {
+            client: Some(self.chat_client()),
+            to: self.req.direct_to.clone().unwrap(),
+            message,
+        }
+    }
+
     fn sd_req(&self) -> chat::SendRequest {
-        let c = Some(chat::Client {
-            user: Some(chat::User {
-                name: self.username.clone(),
-                password: self.password.clone(),
-                gender: Some(1),
-            }),
-            device: Some(chat::Device::default()),
-        });
+        let c = Some(self.chat_client());
         chat::SendRequest {
             client: c.clone(),
             roomname: self.req.roomname.clone().unwrap(),
             message: Some(chat::Message{
                 client: c.clone(),
                 bytes: self.req.send_str.clone().unwrap().as_bytes().to_vec(),
-                time: common::now_milli_seconds(),
-                msg_type: chat::MessageType::Text as i32,
+                msg_type: self.req.blob.as_ref().map(|b| message_type(&b.filename)).unwrap_or(chat::MessageType::Text) as i32,
+                blob: self.req.blob.clone(),
+                reply_to: self.req.reply_to.clone().unwrap_or_default(),
+                // id, seq and time are assigned by the server
+                ..Default::default()
             }),
             room_password: self.req.room_password.clone(), 
         }
     }
 }
+
+// 服务器因为发送太快拒绝请求时，返回建议等待的时间
+pub fn retry_after(err: &(dyn std::error::Error + 'static)) -> Option<std::time::Duration> {
+    let status = err.downcast_ref::<tonic::Status>()?;
+    if status.code() != tonic::Code::ResourceExhausted {
+        return None;
+    }
+    let millis = status.metadata().get(common::RETRY_AFTER_METADATA)?.to_str().ok()?.parse().ok()?;
+    Some(std::time::Duration::from_millis(millis))
+}
+
+// 按扩展名猜测文件的信息类型
+fn message_type(filename: &str) -> chat::MessageType {
+    let extension = std::path::Path::new(filename).extension()
+        .and_then(|e| e.to_str())
+        .unwrap_or_default()
+        .to_ascii_lowercase();
+    match extension.as_str() {
+        "png" | "jpg" | "jpeg" | "gif" | "webp" | "bmp" => chat::MessageType::Image,
+        "mp4" | "webm" | "mov" | "mkv" | "avi" => chat::MessageType::Video,
+        _ => chat::MessageType::Unknown,
+    }
+}
+
+// YYYY-MM-DD当天0点(UTC)的毫秒数
+fn day_start(date: &str) -> Result<u64, Box<dyn std::error::Error>> {
+    let day = chrono::NaiveDate::parse_from_str(date, "%Y-%m-%d")
+        .map_err(|e| format!("invalid date {:?}: {}", date, e))?;
+    Ok(day.and_hms_opt(0, 0, 0).unwrap().and_utc().timestamp_millis().max(0) as u64)
+}
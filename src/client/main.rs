@@ -1,8 +1,8 @@
 #![allow(dead_code)]
 
 use chatserver::client::clib;
+use chatserver::chat;
 use colored::Colorize;
-use chatserver::chat::chat_client::ChatClient;
 use clap::Parser;
 
 #[derive(Debug, Parser)]
@@ -10,6 +10,17 @@ use clap::Parser;
 struct Args {
     #[arg(long)]
     address: String,
+    /// CA certificate of the server, enables TLS
+    #[arg(long)]
+    tls_ca: Option<String>,
+    /// client certificate for mutual TLS
+    #[arg(long, requires_all = ["tls_ca", "tls_key"])]
+    tls_cert: Option<String>,
+    #[arg(long, requires = "tls_cert")]
+    tls_key: Option<String>,
+    /// server name in the certificate, defaults to the host of the address
+    #[arg(long, requires = "tls_ca")]
+    tls_domain: Option<String>,
 }
 
 fn prompt(prompt: &str) -> Result<String, Box<dyn std::error::Error>> {
@@ -48,18 +59,179 @@ fn dump_usage() {
     println!("\tlistr -- list rooms");
     println!("\tlistu -- list users");
     println!("\tjoin <roomname> [password]");
+    println!("\taccept <roomname> <code> -- join a room with an invite code");
+    println!("\tdm <username> -- talk to a user privately");
+    println!("\tlistc -- list direct conversations");
+    println!("\tin a room: /edit <text> -- change your last message, /delete -- delete it");
+    println!("\tin a room: /reply <number> <text> -- reply to message #number, /thread <number> -- show its thread");
+    println!("\tin a room: /react <number> <emoji>, /unreact <number> <emoji> -- react to message #number");
+    println!("\tin a room: /send-file <path> -- send an image, video or file, /save <number> <path> -- save the file of message #number");
+    println!("\tin a room: /more -- show older messages");
+    println!("\tin a room: /kick, /ban, /unban, /promote, /demote <username> -- moderate the room");
+    println!("\tin a room: /rename <name>, /delete-room, /transfer <username> -- manage a room you own");
+    println!("\tin a room: /set password <password|->, /set history <y|n>, /set invite <y|n> -- change the settings of a room you own");
+    println!("\tin a room: /invite [uses] [hours] -- create an invite code, /invites -- list them, /revoke <code> -- revoke one");
+    println!("\tin a room: /search <words> [from:<user>] [since:<YYYY-MM-DD>] [until:<YYYY-MM-DD>] -- search the history");
+}
+
+// 进入房间后的交互：推送的事件和用户输入交替处理，直到输入exit()
+async fn chat(client: &mut clib::Client) -> Result<(), Box<dyn std::error::Error>> {
+    let mut stream = client.subscribe().await?;
+
+    let (sender, mut receiver) = tokio::sync::mpsc::unbounded_channel();
+    let username = client.username.clone();
+    let handle = std::thread::spawn(move || {
+        loop {
+            let inputmsg = prompt(format!("{}: ", username.yellow()).as_str()).unwrap();
+            if inputmsg.is_empty() {
+                continue;
+            }
+            sender.send(inputmsg.clone()).unwrap();
+            if inputmsg == "exit()" {
+                break;
+            }
+        }
+    });
+
+    let mut stream_closed = false;
+    loop {
+        tokio::select! {
+            tosd = receiver.recv() => {
+                let tosd = tosd.unwrap();
+                if tosd == "exit()" {
+                    client.exitroom().await?;
+                    break;
+                }
+                let result = if let Some(text) = tosd.strip_prefix("/edit ") {
+                    client.edit_last(text).await
+                } else if tosd == "/delete" {
+                    client.delete_last().await
+                } else if let Some(rest) = tosd.strip_prefix("/reply ") {
+                    match rest.split_once(' ').map(|(seq, text)| (seq.trim_start_matches('#').parse::<u64>(), text)) {
+                        Some((Ok(seq), text)) => client.reply(seq, text).await,
+                        _ => Err("usage: /reply <number> <text>".into()),
+                    }
+                } else if let Some((command, rest)) = tosd.split_once(' ').filter(|(c, _)| *c == "/react" || *c == "/unreact") {
+                    match rest.split_once(' ').map(|(seq, emoji)| (seq.trim_start_matches('#').parse::<u64>(), emoji.trim())) {
+                        Some((Ok(seq), emoji)) => client.react(seq, emoji, command == "/react").await,
+                        _ => Err(format!("usage: {} <number> <emoji>", command).into()),
+                    }
+                } else if let Some((action, username)) = tosd.strip_prefix('/').and_then(|c| c.split_once(' '))
+                    .filter(|(action, _)| ["kick", "ban", "unban", "promote", "demote"].contains(action)) {
+                    client.moderate(action, username.trim()).await
+                } else if let Some(name) = tosd.strip_prefix("/rename ") {
+                    client.rename_room(name.trim()).await
+                } else if tosd == "/delete-room" {
+                    client.delete_room().await
+                } else if let Some(username) = tosd.strip_prefix("/transfer ") {
+                    client.transfer(username.trim()).await
+                } else if let Some(setting) = tosd.strip_prefix("/set ") {
+                    let settings = match setting.trim().split_once(' ') {
+                        Some(("password", "-")) => Some(chat::UpdateRoomRequest { password: Some(String::new()), ..Default::default() }),
+                        Some(("password", password)) => Some(chat::UpdateRoomRequest { password: Some(password.to_string()), ..Default::default() }),
+                        Some(("history", v)) => Some(chat::UpdateRoomRequest { history_visible: Some(v != "n"), ..Default::default() }),
+                        Some(("invite", v)) => Some(chat::UpdateRoomRequest { invite_only: Some(v != "n"), ..Default::default() }),
+                        _ => None,
+                    };
+                    match settings {
+                        Some(settings) => client.update_room(settings).await,
+                        None => Err("usage: /set password <password|->, /set history <y|n>, /set invite <y|n>".into()),
+                    }
+                } else if let Some(rest) = tosd.strip_prefix("/invite").filter(|r| r.is_empty() || r.starts_with(' ')) {
+                    let mut args = rest.split_whitespace();
+                    let uses = args.next().map_or(Ok(1), str::parse::<u32>);
+                    let hours = args.next().map_or(Ok(24), str::parse::<u64>);
+                    match (uses, hours, args.next()) {
+                        (Ok(uses), Ok(hours), None) => client.create_invite(uses, hours).await,
+                        _ => Err("usage: /invite [uses] [hours]".into()),
+                    }
+                } else if tosd == "/invites" {
+                    client.list_invites().await
+                } else if let Some(code) = tosd.strip_prefix("/revoke ") {
+                    client.revoke_invite(code.trim()).await
+                } else if tosd == "/more" {
+                    client.more().await
+                } else if let Some(input) = tosd.strip_prefix("/search ") {
+                    client.search(input).await
+                } else if let Some(path) = tosd.strip_prefix("/send-file ") {
+                    client.send_file(path.trim()).await
+                } else if let Some(rest) = tosd.strip_prefix("/save ") {
+                    match rest.trim().split_once(' ').map(|(seq, path)| (seq.trim_start_matches('#').parse::<u64>(), path.trim())) {
+                        Some((Ok(seq), path)) => client.save(seq, path).await,
+                        _ => Err("usage: /save <number> <path>".into()),
+                    }
+                } else if let Some(seq) = tosd.strip_prefix("/thread ") {
+                    match seq.trim().trim_start_matches('#').parse::<u64>() {
+                        Ok(seq) => client.showthread(seq).await,
+                        Err(_) => Err("usage: /thread <number>".into()),
+                    }
+                } else {
+                    client.req.send_str = Some(tosd);
+                    client.send().await
+                };
+                if let Err(e) = result {
+                    match clib::retry_after(e.as_ref()) {
+                        Some(wait) => println!("\r{}", format!("You are sending messages a bit fast, please wait {:.1}s and try again",
+                            wait.as_secs_f64()).yellow()),
+                        None => println!("\r{}", e.to_string().red()),
+                    }
+                }
+            },
+            msg = stream.message(), if !stream_closed => {
+                match msg {
+                    Ok(Some(msg)) => {
+                        if let Err(e) = client.catch_up(&msg).await {
+                            println!("\r{}", e.to_string().red());
+                        }
+                        client.recv(&msg);
+                    },
+                    Ok(None) => {
+                        println!("\r{}", "room stream closed by server, type exit() to leave".red());
+                        stream_closed = true;
+                    },
+                    // 推送流落后被服务器断开，从last_seq之后重新订阅
+                    Err(status) if status.code() == tonic::Code::DataLoss => {
+                        stream = client.subscribe().await?;
+                    },
+                    Err(status) => {
+                        println!("\r{}: {}", "room stream closed".red(), status.message());
+                        stream_closed = true;
+                    },
+                }
+            },
+        }
+    }
+
+    handle.join().unwrap();
+    Ok(())
 }
 
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn std::error::Error>> {
     let args = Args::parse();
     let addr = args.address;
+    let tls = args.tls_ca.map(|ca| clib::TlsOptions {
+        ca,
+        cert: args.tls_cert,
+        key: args.tls_key,
+        domain: args.tls_domain,
+    });
+    // the server binds this device to our certificate
+    let device = match tls.as_ref().and_then(|t| t.cert.as_ref()) {
+        Some(cert) => chatserver::common::pem_common_name(&std::fs::read(cert)?)
+            .ok_or("client certificate has no common name")?,
+        None => String::new(),
+    };
 
     let clientstate = std::sync::Arc::new(std::sync::RwLock::new(clib::ClientState{
-        channel: ChatClient::connect(format!("http://{addr}")).await?,
+        channel: clib::connect(&addr, tls.as_ref()).await?,
         lastupdate_time: 0,
         cur_roomname: None,
-        msgnum: 0,
+        last_seq: 0,
+        oldest_seq: 0,
+        last_sent: None,
+        threads: std::collections::HashMap::new(),
+        token: None,
     }));
     println!("Connected to {}!", addr);
     println!();
@@ -71,6 +243,7 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
         state: clientstate,
         username: username.clone(),
         password: password.clone(),
+        device,
     };
 
     loop {
@@ -89,7 +262,7 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
         let input = prompt("> ").unwrap();
         let args: Vec<String> = input.split_whitespace().map(|s| s.to_string()).collect();
 
-        if args.len() == 0 {
+        if args.is_empty() {
             continue;
         }
 
@@ -108,6 +281,10 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
                     None => Some(false),
                 },
                 send_str: None,
+                direct_to: None,
+                reply_to: None,
+                blob: None,
+                invite_code: None,
             };
             client.createroom().await?;
         } else if args[0] == "join" {
@@ -116,47 +293,68 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
                 room_password: args.get(2).cloned(),
                 history_visible: None,
                 send_str: None,
+                direct_to: None,
+                reply_to: None,
+                blob: None,
+                invite_code: None,
             };
 
-            client.join().await?;
-
-            let (sender, receiver) = std::sync::mpsc::channel();
-            let username = client.username.clone();
-            let handle = std::thread::spawn(move || {
-                loop {
-                    let inputmsg = prompt(format!("{}: ", username.yellow()).as_str()).unwrap();
-                    if inputmsg.is_empty() {
-                        continue;
-                    }
-                    sender.send(inputmsg.clone()).unwrap();
-                    if inputmsg == "exit()" {
-                        break;
-                    }
-                }
-            });
-
-            loop {
-                let tosd = receiver.try_recv();
-                if tosd.is_ok() {
-                    if tosd.as_ref().unwrap() == "exit()" {
-                        client.exitroom().await?;
-                        break;
-                    }
-                    client.req.send_str = Some(tosd.unwrap());
-                    client.send().await?;
-                } else {
-                    client.update().await?;
+            let mut admitted = match client.join().await {
+                Ok(admitted) => admitted,
+                Err(e) => {
+                    println!("{}", e.to_string().red());
+                    continue;
+                },
+            };
+            while !admitted {
+                println!("{}", "Room password is wrong".red());
+                let room_password = prompt("give the room password (empty to cancel): ").unwrap();
+                if room_password.is_empty() {
+                    break;
                 }
-                std::thread::sleep(std::time::Duration::from_millis(10));
+                client.req.room_password = Some(room_password);
+                admitted = client.join().await?;
             }
-
-            handle.join().unwrap();
+            if !admitted {
+                continue;
+            }
+            chat(&mut client).await?;
+        } else if args[0] == "accept" && args.len() == 3 {
+            client.req = clib::ClientReq {
+                roomname: Some(args[1].clone()),
+                invite_code: Some(args[2].clone()),
+                ..Default::default()
+            };
+            let result = client.join().await;
+            client.req.invite_code = None;
+            match result {
+                Ok(true) => chat(&mut client).await?,
+                Ok(false) => println!("{}", "Room password is wrong".red()),
+                Err(e) => println!("{}", e.to_string().red()),
+            }
+        } else if args[0] == "dm" && args.len() == 2 {
+            client.req = clib::ClientReq {
+                direct_to: Some(args[1].clone()),
+                ..Default::default()
+            };
+            match client.open_direct().await {
+                Ok(roomname) => client.req.roomname = Some(roomname),
+                Err(e) => {
+                    println!("{}", e.to_string().red());
+                    continue;
+                },
+            }
+            client.join().await?;
+            chat(&mut client).await?;
         } else if args[0] == "exit" {
+            client.logout().await?;
             break;
         } else if args[0] == "listr" {
             client.listrooms().await?;            
         } else if args[0] == "listu" {
             client.listusers().await?;            
+        } else if args[0] == "listc" {
+            client.listconversations().await?;
         } else {
             println!("Unknown command");
             dump_usage();
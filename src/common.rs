@@ -5,10 +5,23 @@ use crate::chat;
 use std::sync::RwLockReadGuard;
 use std::sync::RwLockWriteGuard;
 
+// 客户端通过此metadata携带signup返回的会话令牌
+pub const AUTH_METADATA: &str = "authorization";
+pub const BEARER_PREFIX: &str = "Bearer ";
+// 服务器在ResourceExhausted时通过此metadata告知客户端多少毫秒后可以重试
+pub const RETRY_AFTER_METADATA: &str = "retry-after-ms";
+
 pub fn now_milli_seconds() -> u64 {
     SystemTime::now().duration_since(UNIX_EPOCH).expect("Time went backwards").as_millis() as u64
 }
 
+// len个随机字节的十六进制表示，用作会话令牌和信息id
+pub fn random_hex(len: usize) -> String {
+    use rand::Rng;
+    let mut rng = rand::thread_rng();
+    (0..len).map(|_| format!("{:02x}", rng.gen::<u8>())).collect()
+}
+
 pub fn human_milli_seconds(millis: u64) -> String {
      // 使用标准库将毫秒数转换为 SystemTime
     let duration = Duration::from_millis(millis);
@@ -18,17 +31,77 @@ pub fn human_milli_seconds(millis: u64) -> String {
     let datetime: chrono::DateTime<chrono::Utc> = system_time.into();
     
     // 使用 chrono 格式化时间为字符串
-    let datetime_str = datetime.to_rfc2822(); 
-    datetime_str
+    datetime.to_rfc2822()
+}
+
+// 写入以此开头的临时文件后再rename，崩溃时留下的临时文件在启动时清理。
+// 用户名和房间名不能以.开头，数据文件不会和它重名
+pub const TMP_PREFIX: &str = ".tmp-";
+
+// 1536 -> "1.5 KiB"
+pub fn human_bytes(bytes: u64) -> String {
+    const UNITS: [&str; 4] = ["KiB", "MiB", "GiB", "TiB"];
+    if bytes < 1024 {
+        return format!("{} B", bytes);
+    }
+    let mut size = bytes as f64 / 1024.0;
+    let mut unit = 0;
+    while size >= 1024.0 && unit < UNITS.len() - 1 {
+        size /= 1024.0;
+        unit += 1;
+    }
+    format!("{:.1} {}", size, UNITS[unit])
+}
+
+// 上传和下载文件时每块的字节数
+pub const BLOB_CHUNK: usize = 64 * 1024;
+
+// 先写临时文件并fsync，再rename覆盖目标文件，崩溃时目标文件要么是旧内容要么是新内容
+pub fn write_file_atomic(filepath: &str, buf: &[u8]) -> std::io::Result<()> {
+    use std::io::Write;
+    let path = std::path::Path::new(filepath);
+    let parent = path.parent()
+        .filter(|p| !p.as_os_str().is_empty())
+        .unwrap_or(std::path::Path::new("."));
+    let filename = path.file_name().and_then(|f| f.to_str()).unwrap_or_default();
+    let tmppath = parent.join(format!("{}{}", TMP_PREFIX, filename));
+    let mut file = std::fs::File::create(&tmppath)?;
+    file.write_all(buf)?;
+    file.sync_all()?;
+    drop(file);
+    std::fs::rename(&tmppath, filepath)?;
+    // make the rename itself durable
+    if let Ok(dir) = std::fs::File::open(parent) {
+        let _ = dir.sync_all();
+    }
+    Ok(())
+}
+
+// 证书主题中的CN，启用mutual TLS时作为设备的serial_number
+pub fn certificate_common_name(der: &[u8]) -> Option<String> {
+    let (_, cert) = x509_parser::parse_x509_certificate(der).ok()?;
+    let cn = cert.subject().iter_common_name().next()?;
+    cn.as_str().ok().map(|s| s.to_string())
+}
+
+pub fn pem_common_name(pem: &[u8]) -> Option<String> {
+    let (_, pem) = x509_parser::pem::parse_x509_pem(pem).ok()?;
+    certificate_common_name(&pem.contents)
+}
+
+// 私聊会话对应的房间名以此开头，普通房间不能使用
+pub const DIRECT_PREFIX: &str = "dm:";
+
+// 两个用户之间私聊的房间名，与参数顺序无关
+pub fn direct_roomname(user1: &str, user2: &str) -> String {
+    let (first, second) = if user1 <= user2 { (user1, user2) } else { (user2, user1) };
+    format!("{}{}:{}", DIRECT_PREFIX, first, second)
 }
 
 pub fn client_equal(c1: &chat::Client, c2: &chat::Client) -> bool{
     let thisname = &c1.user.as_ref().unwrap().name;
     let othername = &c2.user.as_ref().unwrap().name;
-    if thisname == othername {
-        return true;
-    }
-    return false;
+    thisname == othername
 }
 
 pub fn client_in_room(client: &chat::Client, room: &RwLockReadGuard<chat::Room>) -> bool {
@@ -38,7 +111,7 @@ pub fn client_in_room(client: &chat::Client, room: &RwLockReadGuard<chat::Room>)
             return true;
         }
     }
-    return false;
+    false
 }
 
 pub fn client_in_room_w(client: &chat::Client, room: &RwLockWriteGuard<chat::Room>) -> bool {
@@ -48,7 +121,7 @@ pub fn client_in_room_w(client: &chat::Client, room: &RwLockWriteGuard<chat::Roo
             return true;
         }
     }
-    return false;
+    false
 }
 
 #[cfg(test)]
@@ -61,6 +134,13 @@ mod tests {
         println!("{now}");
     }
 
+    #[test]
+    fn human_bytes_test() {
+        assert_eq!(human_bytes(0), "0 B");
+        assert_eq!(human_bytes(1536), "1.5 KiB");
+        assert_eq!(human_bytes(16 * 1024 * 1024), "16.0 MiB");
+    }
+
     #[test]
     fn cur_dir() {
         println!("{}", std::env::current_dir().unwrap().to_str().unwrap());
@@ -1,7 +1,12 @@
+// rpc helpers return tonic::Status directly, which is a large error type
+#![allow(clippy::result_large_err)]
+
 pub mod common;
 pub mod client;
 pub mod server;
 
+// tonic names the stream type of a server-streaming rpc after the rpc itself
+#[allow(non_camel_case_types)]
 pub mod chat {
     tonic::include_proto!("chat");
 }
@@ -35,47 +40,314 @@ pub fn log_init() -> Result<(), SetLoggerError> {
 
 impl std::fmt::Display for chat::Message {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> Result<(), std::fmt::Error> {
-        let username = &self.client.as_ref().unwrap().user.as_ref().unwrap().name;
+        let username = self.author().unwrap_or_default();
+        if self.deleted {
+            return write!(f, "{}: {}", username.green().bold(), "(deleted)".dimmed());
+        }
         // let milli =  self.time;
-        let msg = String::from_utf8(self.bytes.clone()).unwrap();
+        // 内容不一定是合法的utf8，不能unwrap
+        let msg = String::from_utf8_lossy(&self.bytes);
         // write!(f, "[{}] {}: {}", common::human_milli_seconds(milli), username, msg)?;
         write!(f, "{}: {}", username.green().bold(), msg)?;
+        if let Some(blob) = &self.blob {
+            let kind = match self.msg_type() {
+                chat::MessageType::Image => "image",
+                chat::MessageType::Video => "video",
+                _ => "file",
+            };
+            let label = format!("[{} {}, {}]", kind, blob.filename, common::human_bytes(blob.size));
+            write!(f, "{}{}", if msg.is_empty() { "" } else { " " }, label.cyan())?;
+        }
+        if !self.revisions.is_empty() {
+            write!(f, " {}", "(edited)".dimmed())?;
+        }
+        for reaction in &self.reactions {
+            write!(f, "  {} {}", reaction.emoji, reaction.usernames.len())?;
+        }
         Ok(())
     } 
 }
 
 impl chat::Room {
-    pub fn from_file(filepath: &String) -> Result<Self, Box<dyn std::error::Error>> {
+    pub fn from_file(filepath: &str) -> Result<Self, Box<dyn std::error::Error>> {
         let buf = std::fs::read(filepath)?;
-        Ok(prost::Message::decode(&buf[..]).unwrap())
+        Ok(prost::Message::decode(&buf[..])?)
     }
 
-    pub fn to_file(&self, filepath: &String) -> Result<(), Box<dyn std::error::Error>> {
+    pub fn to_file(&self, filepath: &str) -> Result<(), Box<dyn std::error::Error>> {
         use prost::Message;
         let mut buf = vec![];
         self.encode(&mut buf)?;
-        std::fs::write(filepath, buf)?;
+        common::write_file_atomic(filepath, &buf)?;
         Ok(())
     }
 }
 
 impl chat::User {
-    pub fn from_file(filepath: &String) -> Result<Self, Box<dyn std::error::Error>> {
+    pub fn from_file(filepath: &str) -> Result<Self, Box<dyn std::error::Error>> {
         let buf = std::fs::read(filepath)?;
-        Ok(prost::Message::decode(&buf[..]).unwrap())
+        Ok(prost::Message::decode(&buf[..])?)
     }
 
-    pub fn to_file(&self, filepath: &String) -> Result<(), Box<dyn std::error::Error>> {
+    pub fn to_file(&self, filepath: &str) -> Result<(), Box<dyn std::error::Error>> {
         use prost::Message;
         let mut buf = vec![];
         self.encode(&mut buf)?;
-        std::fs::write(filepath, buf)?;
+        common::write_file_atomic(filepath, &buf)?;
         Ok(())
     }
 }
 
+impl chat::Room {
+    // 旧版本会把客户端发来的用户密码随client一起存进房间，返回是否有改动
+    pub fn clear_passwords(&mut self) -> bool {
+        let mut changed = false;
+        for client in self.clients.iter_mut()
+            .chain(self.manner.iter_mut())
+            .chain(self.messages.iter_mut().filter_map(|m| m.client.as_mut())) {
+            changed |= client.clear_password();
+        }
+        changed
+    }
+
+    // 成员可见的第一条信息的下标
+    // 成员只能看到seq大于这个值的信息
+    pub fn join_seq(&self, username: &str) -> u64 {
+        if self.history_visible {
+            return 0;
+        }
+        // 没有记录的成员是在此功能之前加入的，历史对其可见
+        self.join_points.get(username).copied().unwrap_or(0)
+    }
+
+    pub fn visible_from(&self, username: &str) -> usize {
+        let join_seq = self.join_seq(username);
+        self.messages.partition_point(|m| m.seq <= join_seq)
+    }
+
+    pub fn visible_messages(&self, username: &str) -> &[chat::Message] {
+        &self.messages[self.visible_from(username)..]
+    }
+
+    // 成员可见的信息中seq大于after_seq的部分
+    pub fn visible_after(&self, username: &str, after_seq: u64) -> &[chat::Message] {
+        let visible = self.visible_messages(username);
+        &visible[visible.partition_point(|m| m.seq <= after_seq)..]
+    }
+
+    // 成员可见的信息中seq小于before_seq(为0时不限)的最后limit条，以及之前是否还有
+    pub fn visible_before(&self, username: &str, before_seq: u64, limit: usize) -> (&[chat::Message], bool) {
+        let visible = self.visible_messages(username);
+        let end = if before_seq == 0 { visible.len() } else { visible.partition_point(|m| m.seq < before_seq) };
+        let start = end.saturating_sub(limit);
+        (&visible[start..end], start > 0)
+    }
+
+    pub fn message_index(&self, id: &str) -> Option<usize> {
+        self.messages.iter().rposition(|m| m.id == id)
+    }
+
+    // 成员可见的以root_id为根的讨论串，回复总在被回复的信息之后，一次遍历即可
+    pub fn thread(&self, username: &str, root_id: &str) -> Option<Vec<chat::Message>> {
+        let visible = self.visible_messages(username);
+        let root = visible.iter().position(|m| m.id == root_id)?;
+        let mut ids = std::collections::HashSet::from([root_id]);
+        let mut thread = vec![visible[root].clone()];
+        for message in &visible[root + 1..] {
+            if ids.contains(message.reply_to.as_str()) {
+                ids.insert(&message.id);
+                thread.push(message.clone());
+            }
+        }
+        Some(thread)
+    }
+
+    pub fn is_owner(&self, username: &str) -> bool {
+        self.manner.as_ref().is_some_and(|c| c.username() == username)
+    }
+
+    pub fn is_member(&self, username: &str) -> bool {
+        self.clients.iter().any(|c| c.username() == username)
+    }
+
+    pub fn role(&self, username: &str) -> chat::Role {
+        if self.is_owner(username) {
+            return chat::Role::Owner;
+        }
+        match self.roles.get(username).and_then(|r| chat::Role::try_from(*r).ok()) {
+            Some(role @ (chat::Role::Banned | chat::Role::Moderator)) => role,
+            _ if self.is_member(username) => chat::Role::Member,
+            _ => chat::Role::NotMember,
+        }
+    }
+
+    pub fn is_banned(&self, username: &str) -> bool {
+        self.role(username) == chat::Role::Banned
+    }
+
+    // 管理员及以上可以管理级别比自己低的用户
+    pub fn can_moderate(&self, actor: &str, target: &str) -> bool {
+        let rank = |role| match role {
+            chat::Role::Owner => 3,
+            chat::Role::Moderator => 2,
+            chat::Role::Member => 1,
+            chat::Role::NotMember | chat::Role::Banned => 0,
+        };
+        let actor = rank(self.role(actor));
+        actor >= 2 && actor > rank(self.role(target))
+    }
+
+    // 移出成员列表，管理员身份一并取消
+    pub fn remove_member(&mut self, username: &str) {
+        self.clients.retain(|c| c.username() != username);
+        if self.roles.get(username) == Some(&(chat::Role::Moderator as i32)) {
+            self.roles.remove(username);
+        }
+    }
+
+    pub fn last_seq(&self) -> u64 {
+        self.messages.last().map(|m| m.seq).unwrap_or(0)
+    }
+
+    // 旧版本的信息没有id和seq，补上并返回补过的下标
+    pub fn assign_sequence(&mut self) -> Vec<usize> {
+        let mut assigned = vec![];
+        for (index, message) in self.messages.iter_mut().enumerate() {
+            if message.seq == 0 {
+                message.seq = index as u64 + 1;
+                message.id = common::random_hex(16);
+                assigned.push(index);
+            }
+        }
+        assigned
+    }
+
+    // 私聊房间中除username之外的另一个成员
+    pub fn peer(&self, username: &str) -> Option<String> {
+        self.clients.iter().map(|c| c.username()).find(|name| name != username)
+    }
+
+    pub fn conversation(&self, username: &str) -> chat::Conversation {
+        let visible = self.visible_messages(username);
+        chat::Conversation {
+            peer: self.peer(username).unwrap_or_default(),
+            roomname: self.name.clone(),
+            last_message: visible.last().cloned(),
+            message_count: visible.len() as u32,
+        }
+    }
+
+    pub fn summary(&self, online_users: Vec<String>, away_users: Vec<String>) -> chat::RoomSummary {
+        chat::RoomSummary {
+            name: self.name.clone(),
+            manner: self.manner.as_ref().map(|c| c.username()).unwrap_or_default(),
+            online_users,
+            away_users,
+            has_password: self.password.is_some(),
+            history_visible: self.history_visible,
+            invite_only: self.invite_only,
+        }
+    }
+
+    // 去掉过期的邀请
+    pub fn prune_invites(&mut self, now: u64) {
+        self.invites.retain(|i| i.expires > now);
+    }
+
+    // 使用一次邀请码，无效或过期时返回false
+    pub fn use_invite(&mut self, code: &str, now: u64) -> bool {
+        self.prune_invites(now);
+        let Some(index) = self.invites.iter().position(|i| i.code == code) else {
+            return false;
+        };
+        if self.invites[index].uses_left <= 1 {
+            self.invites.remove(index);
+        } else {
+            self.invites[index].uses_left -= 1;
+        }
+        true
+    }
+}
+
+impl chat::User {
+    pub fn profile(&self) -> chat::UserProfile {
+        chat::UserProfile {
+            name: self.name.clone(),
+            gender: self.gender,
+        }
+    }
+}
+
+impl chat::Message {
+    pub fn author(&self) -> Option<String> {
+        self.client.as_ref().and_then(|c| c.user.as_ref()).map(|u| u.name.clone())
+    }
+
+    // 当前内容进入编辑历史
+    pub fn edit(&mut self, bytes: Vec<u8>, now: u64) {
+        let since = if self.modified_time > 0 { self.modified_time } else { self.time };
+        self.revisions.push(chat::MessageRevision {
+            bytes: std::mem::replace(&mut self.bytes, bytes),
+            time: since,
+        });
+        self.modified_time = now;
+    }
+
+    // 返回是否有变化，重复添加不计
+    pub fn react(&mut self, emoji: &str, username: &str) -> bool {
+        let index = match self.reactions.iter().position(|r| r.emoji == emoji) {
+            Some(index) => index,
+            None => {
+                self.reactions.push(chat::Reaction { emoji: emoji.to_string(), usernames: vec![] });
+                self.reactions.len() - 1
+            },
+        };
+        let usernames = &mut self.reactions[index].usernames;
+        if usernames.iter().any(|u| u == username) {
+            return false;
+        }
+        usernames.push(username.to_string());
+        true
+    }
+
+    // 返回是否有变化，没有人使用的表情被移除
+    pub fn unreact(&mut self, emoji: &str, username: &str) -> bool {
+        let Some(index) = self.reactions.iter().position(|r| r.emoji == emoji) else {
+            return false;
+        };
+        let usernames = &mut self.reactions[index].usernames;
+        let before = usernames.len();
+        usernames.retain(|u| u != username);
+        let changed = usernames.len() != before;
+        if usernames.is_empty() {
+            self.reactions.remove(index);
+        }
+        changed
+    }
+
+    // 清空内容、编辑历史和表情，只保留id、seq和作者
+    pub fn tombstone(&mut self, deleted_by: &str, now: u64) {
+        self.bytes.clear();
+        self.revisions.clear();
+        self.reactions.clear();
+        self.deleted = true;
+        self.deleted_by = deleted_by.to_string();
+        self.modified_time = now;
+    }
+}
+
 impl chat::Client {
     pub fn username(&self) -> String {
-        self.user.as_ref().unwrap().name.clone()
+        self.user.as_ref().map(|u| u.name.clone()).unwrap_or_default()
+    }
+
+    pub fn clear_password(&mut self) -> bool {
+        match self.user.as_mut() {
+            Some(user) if !user.password.is_empty() => {
+                user.password.clear();
+                true
+            },
+            _ => false,
+        }
     }
 }
@@ -0,0 +1,138 @@
+use std::io::Write;
+use sha2::{Digest, Sha256};
+use crate::common;
+
+pub type BlobResult<T> = Result<T, Box<dyn std::error::Error>>;
+
+// 数据目录下保存上传文件的子目录
+pub const BLOB_DIR: &str = "blobs";
+
+// 按内容寻址的文件存储，每个文件以其内容的sha256命名，相同内容只保存一份
+pub struct BlobStore {
+    dir: String,
+}
+
+impl BlobStore {
+    pub fn new(datapath: &str) -> Self {
+        BlobStore {
+            dir: format!("{}/{}", datapath, BLOB_DIR),
+        }
+    }
+
+    // 删除崩溃时上传到一半的临时文件
+    pub fn clean(&self) -> BlobResult<()> {
+        std::fs::create_dir_all(&self.dir)?;
+        for entry in std::fs::read_dir(&self.dir)? {
+            let entry = entry?;
+            if entry.file_name().to_str().unwrap_or_default().starts_with(common::TMP_PREFIX) {
+                log::warn!("remove unfinished upload {:?}", entry.path());
+                std::fs::remove_file(entry.path())?;
+            }
+        }
+        Ok(())
+    }
+
+    // hash不合法时返回None，避免拼出数据目录之外的路径
+    pub fn path(&self, hash: &str) -> Option<String> {
+        let valid = hash.len() == 64 && hash.bytes().all(|b| b.is_ascii_digit() || (b'a'..=b'f').contains(&b));
+        valid.then(|| format!("{}/{}", self.dir, hash))
+    }
+
+    // 已保存的文件的大小
+    pub fn size(&self, hash: &str) -> Option<u64> {
+        let metadata = std::fs::metadata(self.path(hash)?).ok()?;
+        metadata.is_file().then_some(metadata.len())
+    }
+
+    pub fn writer(&self) -> BlobResult<BlobWriter> {
+        std::fs::create_dir_all(&self.dir)?;
+        let tmppath = format!("{}/{}{}", self.dir, common::TMP_PREFIX, common::random_hex(16));
+        Ok(BlobWriter {
+            file: std::fs::File::create(&tmppath)?,
+            tmppath,
+            dir: self.dir.clone(),
+            hasher: Sha256::new(),
+            size: 0,
+            finished: false,
+        })
+    }
+}
+
+// 边写临时文件边计算hash，finish之前被drop时删除临时文件
+pub struct BlobWriter {
+    file: std::fs::File,
+    tmppath: String,
+    dir: String,
+    hasher: Sha256,
+    size: u64,
+    finished: bool,
+}
+
+impl BlobWriter {
+    pub fn write(&mut self, data: &[u8]) -> BlobResult<()> {
+        self.file.write_all(data)?;
+        self.hasher.update(data);
+        self.size += data.len() as u64;
+        Ok(())
+    }
+
+    pub fn size(&self) -> u64 {
+        self.size
+    }
+
+    // 返回内容的hash
+    pub fn finish(mut self) -> BlobResult<String> {
+        self.file.sync_all()?;
+        let hash: String = self.hasher.clone().finalize().iter().map(|b| format!("{:02x}", b)).collect();
+        let target = format!("{}/{}", self.dir, hash);
+        if std::path::Path::new(&target).is_file() {
+            std::fs::remove_file(&self.tmppath)?;
+        } else {
+            std::fs::rename(&self.tmppath, &target)?;
+        }
+        self.finished = true;
+        Ok(hash)
+    }
+}
+
+impl Drop for BlobWriter {
+    fn drop(&mut self) {
+        if !self.finished {
+            let _ = std::fs::remove_file(&self.tmppath);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::server::storage::tests::TempDir;
+
+    #[test]
+    fn content_addressed() {
+        let dir = TempDir::new();
+        let datapath = dir.path();
+        let blobs = BlobStore::new(datapath);
+        let mut hashes = vec![];
+        for _ in 0..2 {
+            let mut writer = blobs.writer().unwrap();
+            writer.write(b"hello ").unwrap();
+            writer.write(b"world").unwrap();
+            assert_eq!(writer.size(), 11);
+            hashes.push(writer.finish().unwrap());
+        }
+        assert_eq!(hashes[0], "b94d27b9934d3e08a52e52d7da7dabfac484efe37a5380ee9088f7ace2efcde9");
+        assert_eq!(hashes[0], hashes[1]);
+        assert_eq!(blobs.size(&hashes[0]), Some(11));
+        assert_eq!(std::fs::read(blobs.path(&hashes[0]).unwrap()).unwrap(), b"hello world");
+
+        // an abandoned upload leaves nothing behind
+        let mut writer = blobs.writer().unwrap();
+        writer.write(b"partial").unwrap();
+        drop(writer);
+        assert_eq!(std::fs::read_dir(format!("{}/{}", datapath, BLOB_DIR)).unwrap().count(), 1);
+
+        assert!(blobs.path("../room_r1").is_none());
+        assert!(blobs.size(&"0".repeat(64)).is_none());
+    }
+}
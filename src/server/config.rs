@@ -0,0 +1,250 @@
+use std::str::FromStr;
+use serde::Deserialize;
+
+pub type ConfigResult<T> = Result<T, Box<dyn std::error::Error>>;
+
+// 不指定--config时，如果存在就读取这个文件
+pub const DEFAULT_PATH: &str = "src/server/config.toml";
+
+// 服务器配置，对应config.toml，缺省的键使用Default中的值
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    // listen address, e.g. "127.0.0.1:15535"
+    pub addr: String,
+    pub datapath: String,
+    // storage backend: file or sqlite
+    pub storage: String,
+    // off, error, warn, info, debug or trace
+    pub log_level: String,
+    // a user is considered offline in a room after this long without heartbeat
+    pub presence_timeout_ms: u64,
+    // a connected user is considered away after this long without joining or sending
+    pub away_timeout_ms: u64,
+    // largest file accepted by the upload rpc
+    pub max_upload_bytes: u64,
+    pub history: HistoryConfig,
+    pub limits: LimitsConfig,
+    pub tls: Option<TlsConfig>,
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct HistoryConfig {
+    // number of latest messages returned when joining a room
+    pub join_limit: u32,
+    // upper bound of messages returned by a single history request
+    pub page_limit: u32,
+}
+
+// 发送信息的限制，令牌桶最多积攒burst条，每秒补充per_sec条
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct LimitsConfig {
+    // largest message text accepted by send and edit
+    pub max_message_bytes: u32,
+    // every user across all rooms
+    pub user_burst: u32,
+    pub user_per_sec: f64,
+    // every room across all its members
+    pub room_burst: u32,
+    pub room_per_sec: f64,
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct TlsConfig {
+    // PEM encoded certificate chain and private key of the server
+    pub cert: String,
+    pub key: String,
+    // PEM encoded CA used to verify client certificates, enables mutual TLS
+    pub client_ca: Option<String>,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            addr: "127.0.0.1:15535".to_string(),
+            datapath: "data".to_string(),
+            storage: "file".to_string(),
+            log_level: "info".to_string(),
+            presence_timeout_ms: 5000,
+            away_timeout_ms: 300_000,
+            max_upload_bytes: 16 * 1024 * 1024,
+            history: HistoryConfig::default(),
+            limits: LimitsConfig::default(),
+            tls: None,
+        }
+    }
+}
+
+impl Default for HistoryConfig {
+    fn default() -> Self {
+        HistoryConfig {
+            join_limit: 100,
+            page_limit: 500,
+        }
+    }
+}
+
+impl Default for LimitsConfig {
+    fn default() -> Self {
+        LimitsConfig {
+            max_message_bytes: 8192,
+            user_burst: 10,
+            user_per_sec: 2.0,
+            room_burst: 50,
+            room_per_sec: 20.0,
+        }
+    }
+}
+
+impl Config {
+    pub fn from_toml(content: &str) -> ConfigResult<Self> {
+        let config: Config = toml::from_str(content)?;
+        Ok(config)
+    }
+
+    pub fn from_file(path: &str) -> ConfigResult<Self> {
+        let content = std::fs::read_to_string(path)
+            .map_err(|e| format!("read config {}: {}", path, e))?;
+        Self::from_toml(&content).map_err(|e| format!("parse config {}: {}", path, e).into())
+    }
+
+    // 检查各个键的取值，返回第一个错误
+    pub fn validate(&self) -> ConfigResult<()> {
+        if std::net::SocketAddr::from_str(&self.addr).is_err() {
+            return Err(format!("addr: invalid socket address {:?}", self.addr).into());
+        }
+        if self.datapath.is_empty() {
+            return Err("datapath: must not be empty".into());
+        }
+        if !["file", "sqlite"].contains(&self.storage.as_str()) {
+            return Err(format!("storage: expected file or sqlite, got {:?}", self.storage).into());
+        }
+        self.level_filter()?;
+        if self.presence_timeout_ms == 0 {
+            return Err("presence_timeout_ms: must be positive".into());
+        }
+        if self.away_timeout_ms == 0 {
+            return Err("away_timeout_ms: must be positive".into());
+        }
+        if self.max_upload_bytes == 0 {
+            return Err("max_upload_bytes: must be positive".into());
+        }
+        if self.history.join_limit == 0 || self.history.page_limit == 0 {
+            return Err("history: limits must be positive".into());
+        }
+        let limits = &self.limits;
+        if limits.max_message_bytes == 0 || limits.user_burst == 0 || limits.room_burst == 0 {
+            return Err("limits: max_message_bytes and bursts must be positive".into());
+        }
+        // NaN也不满足
+        if !(limits.user_per_sec > 0.0 && limits.room_per_sec > 0.0) {
+            return Err("limits: rates must be positive".into());
+        }
+        if let Some(tls) = &self.tls {
+            let mut files = vec![("tls.cert", &tls.cert), ("tls.key", &tls.key)];
+            if let Some(client_ca) = &tls.client_ca {
+                files.push(("tls.client_ca", client_ca));
+            }
+            for (key, path) in files {
+                if !std::path::Path::new(path).is_file() {
+                    return Err(format!("{}: no such file {:?}", key, path).into());
+                }
+            }
+        }
+        Ok(())
+    }
+
+    pub fn level_filter(&self) -> ConfigResult<log::LevelFilter> {
+        log::LevelFilter::from_str(&self.log_level)
+            .map_err(|_| format!("log_level: unknown level {:?}", self.log_level).into())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_full_config() {
+        let config = Config::from_toml(r#"
+            addr = "0.0.0.0:443"
+            datapath = "/var/lib/chat"
+            storage = "sqlite"
+            log_level = "debug"
+            presence_timeout_ms = 10000
+            away_timeout_ms = 60000
+            max_upload_bytes = 1024
+
+            [history]
+            join_limit = 20
+            page_limit = 50
+
+            [limits]
+            max_message_bytes = 100
+            user_burst = 3
+            user_per_sec = 0.5
+            room_burst = 5
+            room_per_sec = 1
+        "#).unwrap();
+        assert_eq!(config.addr, "0.0.0.0:443");
+        assert_eq!(config.storage, "sqlite");
+        assert_eq!(config.level_filter().unwrap(), log::LevelFilter::Debug);
+        assert_eq!(config.away_timeout_ms, 60000);
+        assert_eq!(config.max_upload_bytes, 1024);
+        assert_eq!(config.history, HistoryConfig { join_limit: 20, page_limit: 50 });
+        assert_eq!(config.limits, LimitsConfig {
+            max_message_bytes: 100,
+            user_burst: 3,
+            user_per_sec: 0.5,
+            room_burst: 5,
+            room_per_sec: 1.0,
+        });
+        assert!(config.tls.is_none());
+        config.validate().unwrap();
+    }
+
+    #[test]
+    fn missing_keys_use_defaults() {
+        let config = Config::from_toml("datapath = \"other\"").unwrap();
+        assert_eq!(config, Config { datapath: "other".to_string(), ..Default::default() });
+        Config::default().validate().unwrap();
+    }
+
+    // 示例配置只能写真实存在的键，取值和Default一致
+    #[test]
+    fn sample_config_matches_defaults() {
+        let config = Config::from_toml(include_str!("config.toml")).unwrap();
+        assert_eq!(config, Config::default());
+    }
+
+    #[test]
+    fn reject_bad_config() {
+        let err = Config::from_toml("adr = \"127.0.0.1:1\"").unwrap_err();
+        assert!(err.to_string().contains("adr"));
+        assert!(Config::from_toml("presence_timeout_ms = \"soon\"").is_err());
+
+        let invalid = [
+            Config { addr: "localhost".to_string(), ..Default::default() },
+            Config { storage: "redis".to_string(), ..Default::default() },
+            Config { log_level: "loud".to_string(), ..Default::default() },
+            Config { presence_timeout_ms: 0, ..Default::default() },
+            Config { max_upload_bytes: 0, ..Default::default() },
+            Config { limits: LimitsConfig { user_burst: 0, ..Default::default() }, ..Default::default() },
+            Config { limits: LimitsConfig { room_per_sec: f64::NAN, ..Default::default() }, ..Default::default() },
+            Config {
+                tls: Some(TlsConfig {
+                    cert: "/nonexistent/cert.pem".to_string(),
+                    key: "/nonexistent/key.pem".to_string(),
+                    client_ca: None,
+                }),
+                ..Default::default()
+            },
+        ];
+        for config in invalid {
+            assert!(config.validate().is_err(), "{:?}", config);
+        }
+    }
+}
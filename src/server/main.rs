@@ -1,21 +1,241 @@
 use chatserver::server::slib;
+use chatserver::server::config::{self, Config, TlsConfig};
+use chatserver::server::session;
+use chatserver::server::tls;
+use chatserver::server::storage::{Storage, FileStorage};
+use chatserver::server::sqlite::SqliteStorage;
 use chatserver::chat::chat_server::ChatServer;
+use std::sync::Arc;
+use std::time::Duration;
+use tonic::service::interceptor::InterceptedService;
+use clap::Parser;
+
+// 收到退出信号后，先通知客户端并给进行中的请求留出的时间
+const DRAIN_PERIOD: Duration = Duration::from_millis(3000);
+// 停止接受连接后最多再等待这么久，然后无论如何写盘退出
+const SHUTDOWN_GRACE: Duration = Duration::from_millis(5000);
+
+// 命令行参数和环境变量覆盖配置文件中的同名键，优先级 命令行 > 环境变量 > 配置文件 > 默认值
+#[derive(Debug, Parser)]
+#[command(version, about)]
+struct Args {
+    /// path of the TOML config file
+    #[arg(long, env = "CHAT_CONFIG")]
+    config: Option<String>,
+    #[arg(long, env = "CHAT_ADDR")]
+    addr: Option<String>,
+    #[arg(long, env = "CHAT_DATAPATH")]
+    datapath: Option<String>,
+    /// file or sqlite
+    #[arg(long, env = "CHAT_STORAGE")]
+    storage: Option<String>,
+    #[arg(long, env = "CHAT_LOG_LEVEL")]
+    log_level: Option<String>,
+    #[arg(long, env = "CHAT_PRESENCE_TIMEOUT_MS")]
+    presence_timeout_ms: Option<u64>,
+    #[arg(long, env = "CHAT_AWAY_TIMEOUT_MS")]
+    away_timeout_ms: Option<u64>,
+    #[arg(long, env = "CHAT_MAX_UPLOAD_BYTES")]
+    max_upload_bytes: Option<u64>,
+    #[arg(long, env = "CHAT_HISTORY_JOIN_LIMIT")]
+    history_join_limit: Option<u32>,
+    #[arg(long, env = "CHAT_HISTORY_PAGE_LIMIT")]
+    history_page_limit: Option<u32>,
+    #[arg(long, env = "CHAT_LIMITS_MAX_MESSAGE_BYTES")]
+    limits_max_message_bytes: Option<u32>,
+    #[arg(long, env = "CHAT_LIMITS_USER_BURST")]
+    limits_user_burst: Option<u32>,
+    #[arg(long, env = "CHAT_LIMITS_USER_PER_SEC")]
+    limits_user_per_sec: Option<f64>,
+    #[arg(long, env = "CHAT_LIMITS_ROOM_BURST")]
+    limits_room_burst: Option<u32>,
+    #[arg(long, env = "CHAT_LIMITS_ROOM_PER_SEC")]
+    limits_room_per_sec: Option<f64>,
+    #[arg(long, env = "CHAT_TLS_CERT", requires = "tls_key")]
+    tls_cert: Option<String>,
+    #[arg(long, env = "CHAT_TLS_KEY", requires = "tls_cert")]
+    tls_key: Option<String>,
+    #[arg(long, env = "CHAT_TLS_CLIENT_CA")]
+    tls_client_ca: Option<String>,
+}
+
+impl Args {
+    fn load_config(self) -> Result<Config, Box<dyn std::error::Error>> {
+        let mut config = match self.config {
+            Some(path) => Config::from_file(&path)?,
+            None if std::path::Path::new(config::DEFAULT_PATH).exists() => Config::from_file(config::DEFAULT_PATH)?,
+            None => Config::default(),
+        };
+        if let Some(addr) = self.addr {
+            config.addr = addr;
+        }
+        if let Some(datapath) = self.datapath {
+            config.datapath = datapath;
+        }
+        if let Some(storage) = self.storage {
+            config.storage = storage;
+        }
+        if let Some(log_level) = self.log_level {
+            config.log_level = log_level;
+        }
+        if let Some(timeout) = self.presence_timeout_ms {
+            config.presence_timeout_ms = timeout;
+        }
+        if let Some(timeout) = self.away_timeout_ms {
+            config.away_timeout_ms = timeout;
+        }
+        if let Some(limit) = self.max_upload_bytes {
+            config.max_upload_bytes = limit;
+        }
+        if let Some(limit) = self.history_join_limit {
+            config.history.join_limit = limit;
+        }
+        if let Some(limit) = self.history_page_limit {
+            config.history.page_limit = limit;
+        }
+        if let Some(limit) = self.limits_max_message_bytes {
+            config.limits.max_message_bytes = limit;
+        }
+        if let Some(burst) = self.limits_user_burst {
+            config.limits.user_burst = burst;
+        }
+        if let Some(rate) = self.limits_user_per_sec {
+            config.limits.user_per_sec = rate;
+        }
+        if let Some(burst) = self.limits_room_burst {
+            config.limits.room_burst = burst;
+        }
+        if let Some(rate) = self.limits_room_per_sec {
+            config.limits.room_per_sec = rate;
+        }
+        // 只替换证书和私钥，配置文件中的client_ca保留
+        if let (Some(cert), Some(key)) = (self.tls_cert, self.tls_key) {
+            let client_ca = config.tls.take().and_then(|tls| tls.client_ca);
+            config.tls = Some(TlsConfig { cert, key, client_ca });
+        }
+        if let Some(client_ca) = self.tls_client_ca {
+            match config.tls.as_mut() {
+                Some(tls) => tls.client_ca = Some(client_ca),
+                None => return Err("tls_client_ca: requires tls cert and key".into()),
+            }
+        }
+        config.validate().map_err(|e| format!("invalid config: {}", e))?;
+        Ok(config)
+    }
+}
 
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn std::error::Error>> {
-    let mut mychatserver = slib::MyChatServer::default();
-    mychatserver.config.read_file("src/server/config");
+    chatserver::log_init().unwrap();
+    let config = Args::parse().load_config()?;
+    log::set_max_level(config.level_filter()?);
+    match config.storage.as_str() {
+        "file" => {
+            let storage = FileStorage::open(&config.datapath)?;
+            serve(slib::MyChatServer::new(config, storage)).await
+        },
+        "sqlite" => {
+            let storage = SqliteStorage::open(&config.datapath)?;
+            serve(slib::MyChatServer::new(config, storage)).await
+        },
+        other => unreachable!("validated storage backend: {}", other),
+    }
+}
+
+async fn serve<S: Storage>(mychatserver: slib::MyChatServer<S>) -> Result<(), Box<dyn std::error::Error>> {
     mychatserver.init()?;
     let addr = mychatserver.config.addr.parse().unwrap();
-    chatserver::log_init().unwrap();
+    let interceptor = session::SessionInterceptor::new(mychatserver.sessions.clone());
+    let mut builder = tonic::transport::Server::builder();
+    if let Some(tlsconfig) = &mychatserver.config.tls {
+        builder = builder.tls_config(tls::server_tls_config(tlsconfig)?)?;
+        let mode = if tlsconfig.client_ca.is_some() { "mutual TLS" } else { "TLS" };
+        log::info!("serve with {}", mode);
+    }
+    // keep a handle to flush the state ourselves once the service is stopped
+    let mychatserver = Arc::new(mychatserver);
+    let service = InterceptedService::new(ChatServer::from_arc(Arc::clone(&mychatserver)), interceptor);
 
-    tonic::transport::Server::builder()
-        .add_service(ChatServer::new(mychatserver))
-        .serve(addr)
-        .await?;
+    let (stop_sender, stop_receiver) = tokio::sync::oneshot::channel::<()>();
+    let mut serving = tokio::spawn(builder
+        .add_service(service)
+        .serve_with_shutdown(addr, async {
+            let _ = stop_receiver.await;
+        }));
 
+    let result = tokio::select! {
+        _ = shutdown_signal() => {
+            log::info!("shutting down, draining clients for {:?}", DRAIN_PERIOD);
+            mychatserver.begin_shutdown();
+            tokio::time::sleep(DRAIN_PERIOD).await;
+            let _ = stop_sender.send(());
+            match tokio::time::timeout(SHUTDOWN_GRACE, &mut serving).await {
+                Ok(joined) => joined?,
+                Err(_) => {
+                    log::warn!("connections still open after {:?}, stop anyway", SHUTDOWN_GRACE);
+                    Ok(())
+                },
+            }
+        },
+        // failed to bind or serve
+        joined = &mut serving => joined?,
+    };
+
+    mychatserver.shutdown();
+    log::info!("server stopped");
+    result?;
     Ok(())
 }
 
+async fn shutdown_signal() {
+    let ctrl_c = async {
+        tokio::signal::ctrl_c().await.expect("install SIGINT handler");
+    };
+    #[cfg(unix)]
+    let terminate = async {
+        tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate())
+            .expect("install SIGTERM handler")
+            .recv()
+            .await;
+    };
+    #[cfg(not(unix))]
+    let terminate = std::future::pending::<()>();
 
+    tokio::select! {
+        _ = ctrl_c => {},
+        _ = terminate => {},
+    }
+}
 
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn cli_tls_override_keeps_client_ca() {
+        let dir = std::env::temp_dir().join(format!("chatserver_test_{}", rand::random::<u64>()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = |name: &str| dir.join(name).to_str().unwrap().to_string();
+        for name in ["file-cert.pem", "file-key.pem", "ca.pem", "cli-cert.pem", "cli-key.pem"] {
+            std::fs::write(path(name), "").unwrap();
+        }
+        std::fs::write(path("config.toml"), format!(
+            "[tls]\ncert = {:?}\nkey = {:?}\nclient_ca = {:?}\n",
+            path("file-cert.pem"), path("file-key.pem"), path("ca.pem"),
+        )).unwrap();
+
+        let args = Args::try_parse_from([
+            "server",
+            "--config", &path("config.toml"),
+            "--tls-cert", &path("cli-cert.pem"),
+            "--tls-key", &path("cli-key.pem"),
+        ]).unwrap();
+        let config = args.load_config();
+        std::fs::remove_dir_all(&dir).unwrap();
+        assert_eq!(config.unwrap().tls, Some(TlsConfig {
+            cert: path("cli-cert.pem"),
+            key: path("cli-key.pem"),
+            client_ca: Some(path("ca.pem")),
+        }));
+    }
+}
@@ -1 +1,12 @@
 pub mod slib;
+pub mod session;
+pub mod password;
+pub mod roomlog;
+pub mod storage;
+pub mod sqlite;
+pub mod config;
+pub mod tls;
+pub mod presence;
+pub mod blob;
+pub mod search;
+pub mod ratelimit;
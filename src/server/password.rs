@@ -0,0 +1,51 @@
+use argon2::Argon2;
+use argon2::password_hash::{PasswordHash, PasswordHasher, PasswordVerifier, SaltString};
+use argon2::password_hash::rand_core::OsRng;
+
+// 用户文件中的password字段保存PHC格式的argon2哈希，例如"$argon2id$v=19$..."
+pub fn hash(password: &str) -> String {
+    let salt = SaltString::generate(&mut OsRng);
+    Argon2::default()
+        .hash_password(password.as_bytes(), &salt)
+        .expect("argon2 hash failed")
+        .to_string()
+}
+
+// argon2内部以常数时间比较哈希值
+pub fn verify(password: &str, hashed: &str) -> bool {
+    match PasswordHash::new(hashed) {
+        Ok(parsed) => Argon2::default().verify_password(password.as_bytes(), &parsed).is_ok(),
+        Err(e) => {
+            log::error!("malformed password hash: {}", e);
+            false
+        },
+    }
+}
+
+// 旧版本的用户文件直接保存明文密码
+pub fn is_hashed(stored: &str) -> bool {
+    stored.starts_with("$argon2")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn hash_and_verify() {
+        // long enough that the base64 output can not contain it by chance
+        let hashed = hash("correct-horse");
+        assert!(is_hashed(&hashed));
+        assert!(!hashed.contains("correct-horse"));
+        assert!(verify("correct-horse", &hashed));
+        assert!(!verify("wrong", &hashed));
+        // salted, so the same password hashes differently
+        assert_ne!(hashed, hash("correct-horse"));
+    }
+
+    #[test]
+    fn legacy_plaintext() {
+        assert!(!is_hashed("pw"));
+        assert!(!verify("pw", "pw"));
+    }
+}
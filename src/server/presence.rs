@@ -0,0 +1,231 @@
+use std::collections::HashMap;
+use crate::chat;
+use crate::chat::PresenceState;
+
+// 用户在某个房间中的存活情况
+#[derive(Debug, Clone)]
+struct Liveness {
+    // last heartbeat or any other request in this room
+    last_seen: u64,
+    // last join or send in this room
+    last_active: u64,
+    // number of open subscribe streams, an open stream keeps the user connected
+    streams: usize,
+    state: PresenceState,
+}
+
+// 以(房间, 用户)为单位跟踪在线状态：
+// 有订阅流或offline_after内有心跳时在线，其中away_after内没有发言的为离开
+pub struct Presence {
+    rooms: HashMap<String, HashMap<String, Liveness>>,
+    away_after: u64,
+    offline_after: u64,
+}
+
+impl Presence {
+    pub fn new(away_after: u64, offline_after: u64) -> Self {
+        Presence {
+            rooms: HashMap::new(),
+            away_after,
+            offline_after,
+        }
+    }
+
+    pub fn add_room(&mut self, roomname: &str) {
+        self.rooms.entry(roomname.to_string()).or_default();
+    }
+
+    // 房间改名，在线状态保持不变
+    pub fn rename_room(&mut self, oldname: &str, newname: &str) {
+        if let Some(users) = self.rooms.remove(oldname) {
+            self.rooms.insert(newname.to_string(), users);
+        }
+    }
+
+    // 房间被删除，不再产生状态变化
+    pub fn remove_room(&mut self, roomname: &str) {
+        self.rooms.remove(roomname);
+    }
+
+    // 收到心跳，只证明连接存活
+    pub fn touch(&mut self, roomname: &str, username: &str, now: u64) -> Option<chat::PresenceChange> {
+        self.update(roomname, username, now, |l| l.last_seen = now)
+    }
+
+    // 用户进入房间或发言
+    pub fn active(&mut self, roomname: &str, username: &str, now: u64) -> Option<chat::PresenceChange> {
+        self.update(roomname, username, now, |l| {
+            l.last_seen = now;
+            l.last_active = now;
+        })
+    }
+
+    // 打开订阅流也算一次活动，刚进入的用户不会先显示为离开
+    pub fn subscribe(&mut self, roomname: &str, username: &str, now: u64) -> Option<chat::PresenceChange> {
+        self.update(roomname, username, now, |l| {
+            l.last_seen = now;
+            l.last_active = now;
+            l.streams += 1;
+        })
+    }
+
+    // 最后一个订阅流结束时立即离线
+    pub fn unsubscribe(&mut self, roomname: &str, username: &str, now: u64) -> Option<chat::PresenceChange> {
+        let has_liveness = self.rooms.get(roomname).is_some_and(|r| r.contains_key(username));
+        if !has_liveness {
+            return None;
+        }
+        self.update(roomname, username, now, |l| {
+            l.streams = l.streams.saturating_sub(1);
+            if l.streams == 0 {
+                l.last_seen = 0;
+            }
+        })
+    }
+
+    // 用户主动退出房间
+    pub fn leave(&mut self, roomname: &str, username: &str, now: u64) -> Option<chat::PresenceChange> {
+        let liveness = self.rooms.get_mut(roomname)?.remove(username)?;
+        if liveness.state == PresenceState::Offline {
+            return None;
+        }
+        Some(change(roomname, username, PresenceState::Offline, now))
+    }
+
+    // 定期调用，返回因超时而变化的状态
+    pub fn sweep(&mut self, now: u64) -> Vec<chat::PresenceChange> {
+        let (away_after, offline_after) = (self.away_after, self.offline_after);
+        let mut changes = vec![];
+        for (roomname, users) in self.rooms.iter_mut() {
+            for (username, liveness) in users.iter_mut() {
+                let state = evaluate(liveness, now, away_after, offline_after);
+                if state != liveness.state {
+                    liveness.state = state;
+                    changes.push(change(roomname, username, state, now));
+                }
+            }
+            users.retain(|_, l| l.state != PresenceState::Offline);
+        }
+        changes
+    }
+
+    pub fn state(&self, roomname: &str, username: &str) -> PresenceState {
+        self.rooms.get(roomname)
+            .and_then(|users| users.get(username))
+            .map(|l| l.state)
+            .unwrap_or(PresenceState::Offline)
+    }
+
+    // 房间中处于state状态的用户，按用户名排序
+    pub fn users(&self, roomname: &str, state: PresenceState) -> Vec<String> {
+        let mut users: Vec<String> = self.rooms.get(roomname)
+            .map(|users| users.iter()
+                .filter(|(_, l)| l.state == state)
+                .map(|(username, _)| username.clone())
+                .collect())
+            .unwrap_or_default();
+        users.sort();
+        users
+    }
+
+    fn update(&mut self, roomname: &str, username: &str, now: u64, apply: impl FnOnce(&mut Liveness))
+        -> Option<chat::PresenceChange> {
+        let (away_after, offline_after) = (self.away_after, self.offline_after);
+        let users = self.rooms.get_mut(roomname)?;
+        let liveness = users.entry(username.to_string()).or_insert(Liveness {
+            last_seen: 0,
+            last_active: 0,
+            streams: 0,
+            state: PresenceState::Offline,
+        });
+        apply(liveness);
+        let state = evaluate(liveness, now, away_after, offline_after);
+        if state == liveness.state {
+            return None;
+        }
+        liveness.state = state;
+        if state == PresenceState::Offline {
+            users.remove(username);
+        }
+        Some(change(roomname, username, state, now))
+    }
+}
+
+fn evaluate(liveness: &Liveness, now: u64, away_after: u64, offline_after: u64) -> PresenceState {
+    let connected = liveness.streams > 0 || now.saturating_sub(liveness.last_seen) <= offline_after;
+    if !connected {
+        PresenceState::Offline
+    } else if now.saturating_sub(liveness.last_active) > away_after {
+        PresenceState::Away
+    } else {
+        PresenceState::Online
+    }
+}
+
+fn change(roomname: &str, username: &str, state: PresenceState, now: u64) -> chat::PresenceChange {
+    chat::PresenceChange {
+        roomname: roomname.to_string(),
+        username: username.to_string(),
+        state: state as i32,
+        time: now,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn states(changes: &[chat::PresenceChange]) -> Vec<(&str, &str, PresenceState)> {
+        changes.iter()
+            .map(|c| (c.roomname.as_str(), c.username.as_str(), c.state()))
+            .collect()
+    }
+
+    #[test]
+    fn liveness_is_per_room() {
+        let mut presence = Presence::new(100, 50);
+        presence.add_room("r1");
+        presence.add_room("r2");
+        assert!(presence.active("r1", "alice", 1000).is_some());
+        assert!(presence.active("r2", "alice", 1000).is_some());
+        // heartbeats in r1 only
+        assert!(presence.touch("r1", "alice", 1040).is_none());
+        assert!(presence.touch("r1", "alice", 1080).is_none());
+
+        let changes = presence.sweep(1090);
+        assert_eq!(states(&changes), [("r2", "alice", PresenceState::Offline)]);
+        assert_eq!(presence.state("r1", "alice"), PresenceState::Online);
+        assert!(presence.users("r2", PresenceState::Online).is_empty());
+
+        presence.rename_room("r1", "r3");
+        assert_eq!(presence.users("r3", PresenceState::Online), ["alice"]);
+        presence.remove_room("r3");
+        assert!(presence.touch("r3", "alice", 1100).is_none());
+        // unknown rooms are ignored
+        assert!(presence.active("nope", "alice", 1090).is_none());
+    }
+
+    #[test]
+    fn online_away_offline() {
+        let mut presence = Presence::new(100, 50);
+        presence.add_room("r1");
+        // subscribing counts as activity
+        let change = presence.subscribe("r1", "alice", 1000).unwrap();
+        assert_eq!(change.state(), PresenceState::Online);
+        assert!(presence.active("r1", "alice", 1010).is_none());
+
+        // a subscribed user never times out, but becomes away when idle
+        assert!(presence.sweep(1100).is_empty());
+        assert_eq!(states(&presence.sweep(1200)), [("r1", "alice", PresenceState::Away)]);
+        assert_eq!(presence.users("r1", PresenceState::Away), ["alice"]);
+        assert_eq!(presence.active("r1", "alice", 1210).unwrap().state(), PresenceState::Online);
+
+        assert_eq!(presence.unsubscribe("r1", "alice", 1220).unwrap().state(), PresenceState::Offline);
+        assert!(presence.unsubscribe("r1", "alice", 1230).is_none());
+        assert!(presence.leave("r1", "alice", 1230).is_none());
+
+        presence.active("r1", "bob", 1300);
+        assert_eq!(presence.leave("r1", "bob", 1310).unwrap().state(), PresenceState::Offline);
+        assert_eq!(presence.state("r1", "bob"), PresenceState::Offline);
+    }
+}
@@ -0,0 +1,134 @@
+use std::collections::HashMap;
+use std::sync::Mutex;
+use crate::server::config::LimitsConfig;
+
+// 令牌桶，updated时刻有tokens个令牌
+struct Bucket {
+    tokens: f64,
+    updated: u64,
+}
+
+// 每个key一个令牌桶，最多积攒burst个令牌，每秒补充per_sec个
+struct Buckets {
+    burst: f64,
+    per_sec: f64,
+    buckets: HashMap<String, Bucket>,
+}
+
+impl Buckets {
+    fn new(burst: u32, per_sec: f64) -> Self {
+        Buckets {
+            burst: burst as f64,
+            per_sec,
+            buckets: HashMap::new(),
+        }
+    }
+
+    // 没有桶的key视为满的
+    fn tokens(&self, key: &str, now: u64) -> f64 {
+        match self.buckets.get(key) {
+            Some(bucket) => {
+                let elapsed = now.saturating_sub(bucket.updated) as f64 / 1000.0;
+                (bucket.tokens + elapsed * self.per_sec).min(self.burst)
+            },
+            None => self.burst,
+        }
+    }
+
+    // 还要等多少毫秒才有一个令牌
+    fn wait(&self, key: &str, now: u64) -> u64 {
+        let missing = 1.0 - self.tokens(key, now);
+        if missing <= 0.0 {
+            0
+        } else {
+            (missing / self.per_sec * 1000.0).ceil() as u64
+        }
+    }
+
+    fn take(&mut self, key: &str, now: u64) {
+        let tokens = self.tokens(key, now) - 1.0;
+        self.buckets.insert(key.to_string(), Bucket { tokens, updated: now });
+    }
+
+    // 满的桶和没有桶一样，可以丢掉
+    fn sweep(&mut self, now: u64) {
+        let (burst, per_sec) = (self.burst, self.per_sec);
+        self.buckets.retain(|_, b| b.tokens + now.saturating_sub(b.updated) as f64 / 1000.0 * per_sec < burst);
+    }
+
+    fn rename(&mut self, oldname: &str, newname: &str) {
+        if let Some(bucket) = self.buckets.remove(oldname) {
+            self.buckets.insert(newname.to_string(), bucket);
+        }
+    }
+}
+
+// 发送信息的频率限制，用户和房间都有令牌时才允许发送，并同时消耗一个
+pub struct RateLimiter {
+    users: Mutex<Buckets>,
+    rooms: Mutex<Buckets>,
+}
+
+impl RateLimiter {
+    pub fn new(config: &LimitsConfig) -> Self {
+        RateLimiter {
+            users: Mutex::new(Buckets::new(config.user_burst, config.user_per_sec)),
+            rooms: Mutex::new(Buckets::new(config.room_burst, config.room_per_sec)),
+        }
+    }
+
+    // 被限制时返回需要等待的毫秒数
+    pub fn acquire(&self, username: &str, roomname: &str, now: u64) -> Result<(), u64> {
+        let mut users = self.users.lock().unwrap();
+        let mut rooms = self.rooms.lock().unwrap();
+        let wait = users.wait(username, now).max(rooms.wait(roomname, now));
+        if wait > 0 {
+            return Err(wait);
+        }
+        users.take(username, now);
+        rooms.take(roomname, now);
+        Ok(())
+    }
+
+    pub fn sweep(&self, now: u64) {
+        self.users.lock().unwrap().sweep(now);
+        self.rooms.lock().unwrap().sweep(now);
+    }
+
+    pub fn rename_room(&self, oldname: &str, newname: &str) {
+        self.rooms.lock().unwrap().rename(oldname, newname);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn token_buckets() {
+        let limiter = RateLimiter::new(&LimitsConfig {
+            user_burst: 2,
+            user_per_sec: 1.0,
+            room_burst: 3,
+            room_per_sec: 0.5,
+            ..Default::default()
+        });
+        // a burst, then one message per second
+        assert_eq!(limiter.acquire("alice", "r1", 0), Ok(()));
+        assert_eq!(limiter.acquire("alice", "r1", 0), Ok(()));
+        assert_eq!(limiter.acquire("alice", "r1", 0), Err(1000));
+        assert_eq!(limiter.acquire("alice", "r1", 400), Err(600));
+        assert_eq!(limiter.acquire("alice", "r2", 1000), Ok(()));
+        // the room is limited across its members and a refused send costs nothing
+        assert_eq!(limiter.acquire("bob", "r1", 1000), Ok(()));
+        assert_eq!(limiter.acquire("carol", "r1", 1000), Err(1000));
+        assert_eq!(limiter.acquire("carol", "r1", 2000), Ok(()));
+
+        // full buckets are dropped
+        limiter.sweep(2000);
+        assert_eq!(limiter.users.lock().unwrap().buckets.len(), 2);
+        limiter.sweep(10_000);
+        assert!(limiter.users.lock().unwrap().buckets.is_empty());
+        assert!(limiter.rooms.lock().unwrap().buckets.is_empty());
+    }
+}
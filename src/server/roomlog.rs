@@ -0,0 +1,162 @@
+use std::io::Write;
+use prost::Message;
+use crate::chat;
+
+// 日志中累积了这么多条记录后，把房间压缩成一次完整的room_<name>快照
+pub const COMPACT_EVERY: usize = 1000;
+
+pub fn room_path(datapath: &str, roomname: &str) -> String {
+    format!("{}/room_{}", datapath, roomname)
+}
+
+pub fn log_path(datapath: &str, roomname: &str) -> String {
+    format!("{}/log_{}", datapath, roomname)
+}
+
+// 每条记录是一个长度前缀的RoomLogEntry
+pub fn append(path: &str, index: usize, message: &chat::Message) -> std::io::Result<()> {
+    write_entry(path, &chat::RoomLogEntry {
+        index: index as u64,
+        message: Some(message.clone()),
+        room: None,
+    })
+}
+
+// 记录房间的成员和设置，信息不写入这条记录
+pub fn append_settings(path: &str, room: &chat::Room) -> std::io::Result<()> {
+    write_entry(path, &chat::RoomLogEntry {
+        room: Some(chat::Room { messages: vec![], ..room.clone() }),
+        ..Default::default()
+    })
+}
+
+fn write_entry(path: &str, entry: &chat::RoomLogEntry) -> std::io::Result<()> {
+    let mut file = std::fs::OpenOptions::new().create(true).append(true).open(path)?;
+    file.write_all(&entry.encode_length_delimited_to_vec())?;
+    file.sync_data()?;
+    Ok(())
+}
+
+// 把快照之后追加或修改的信息和设置补回房间，返回补回的记录数。
+// 快照中已有的信息被记录覆盖，末尾写了一半的记录会被丢弃。
+// 中间的记录无法解析时返回prost::DecodeError，之前的记录已经补回
+pub fn replay(path: &str, room: &mut chat::Room) -> Result<usize, Box<dyn std::error::Error>> {
+    let buf = match std::fs::read(path) {
+        Ok(buf) => buf,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(0),
+        Err(e) => return Err(e.into()),
+    };
+    let mut cursor = &buf[..];
+    let mut replayed = 0;
+    while !cursor.is_empty() {
+        let mut body = cursor;
+        let len = match prost::encoding::decode_varint(&mut body) {
+            Ok(len) => len as usize,
+            // the length prefix itself was cut short
+            Err(_) if cursor.len() < 10 && cursor.iter().all(|b| b & 0x80 != 0) => {
+                log::warn!("drop truncated tail of {}", path);
+                break;
+            },
+            Err(e) => return Err(e.into()),
+        };
+        if len > body.len() {
+            log::warn!("drop truncated tail of {}: {} of {} bytes", path, body.len(), len);
+            break;
+        }
+        let entry = chat::RoomLogEntry::decode(&body[..len])?;
+        cursor = &body[len..];
+        if let Some(settings) = entry.room {
+            let messages = std::mem::take(&mut room.messages);
+            *room = chat::Room { messages, ..settings };
+            replayed += 1;
+            continue;
+        }
+        let index = entry.index as usize;
+        let message = match entry.message {
+            Some(message) if index <= room.messages.len() => message,
+            _ => continue,
+        };
+        if index == room.messages.len() {
+            room.messages.push(message);
+        } else {
+            room.messages[index] = message;
+        }
+        replayed += 1;
+    }
+    Ok(replayed)
+}
+
+pub fn truncate(path: &str) -> std::io::Result<()> {
+    match std::fs::remove_file(path) {
+        Err(e) if e.kind() != std::io::ErrorKind::NotFound => Err(e),
+        _ => Ok(()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn message(text: &str) -> chat::Message {
+        chat::Message {
+            bytes: text.as_bytes().to_vec(),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn append_and_replay() {
+        let path = std::env::temp_dir().join(format!("chatserver_log_{}", rand::random::<u64>()));
+        let path = path.to_str().unwrap();
+        let mut room = chat::Room { messages: vec![message("a")], ..Default::default() };
+        // index 0 is already in the snapshot and gets overwritten
+        append(path, 0, &message("A")).unwrap();
+        append(path, 1, &message("b")).unwrap();
+        append(path, 2, &message("c")).unwrap();
+        // ahead of the room, ignored
+        append(path, 4, &message("e")).unwrap();
+        // simulate a crash in the middle of an append
+        let mut file = std::fs::OpenOptions::new().append(true).open(path).unwrap();
+        file.write_all(&[0x20, 0x08]).unwrap();
+
+        assert_eq!(replay(path, &mut room).unwrap(), 3);
+        let texts: Vec<&[u8]> = room.messages.iter().map(|m| &m.bytes[..]).collect();
+        assert_eq!(texts, [b"A", b"b", b"c"]);
+        truncate(path).unwrap();
+
+        // settings replace the room's but keep its messages
+        let settings = chat::Room {
+            name: "r1".to_string(),
+            history_visible: true,
+            messages: vec![message("ignored")],
+            ..Default::default()
+        };
+        append_settings(path, &settings).unwrap();
+        append(path, 3, &message("d")).unwrap();
+        assert_eq!(replay(path, &mut room).unwrap(), 2);
+        assert!(room.history_visible);
+        let texts: Vec<&[u8]> = room.messages.iter().map(|m| &m.bytes[..]).collect();
+        assert_eq!(texts, [b"A", b"b", b"c", b"d"]);
+
+        truncate(path).unwrap();
+        assert_eq!(replay(path, &mut room).unwrap(), 0);
+    }
+
+    #[test]
+    fn corrupt_entry_is_an_error() {
+        let path = std::env::temp_dir().join(format!("chatserver_log_{}", rand::random::<u64>()));
+        let path = path.to_str().unwrap();
+        append(path, 0, &message("a")).unwrap();
+        // a complete record that does not decode, followed by a good one
+        let mut file = std::fs::OpenOptions::new().append(true).open(path).unwrap();
+        file.write_all(&[0x02, 0xff, 0xff]).unwrap();
+        append(path, 1, &message("b")).unwrap();
+
+        let mut room = chat::Room::default();
+        let err = replay(path, &mut room).unwrap_err();
+        assert!(err.is::<prost::DecodeError>());
+        // entries before the corruption are kept
+        assert_eq!(room.messages.len(), 1);
+        truncate(path).unwrap();
+    }
+}
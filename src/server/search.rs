@@ -0,0 +1,168 @@
+use std::collections::HashMap;
+use crate::chat;
+
+// 每个房间一个倒排索引：词 -> 含有这个词的信息下标，升序
+// 编辑和删除只追加新的词，旧词留下的下标在查询时按信息的当前内容过滤
+#[derive(Default)]
+pub struct SearchIndex {
+    rooms: HashMap<String, HashMap<String, Vec<usize>>>,
+}
+
+// 查询条件，since和until为0时不限
+pub struct SearchQuery<'a> {
+    pub text: &'a str,
+    pub author: Option<&'a str>,
+    pub since: u64,
+    pub until: u64,
+    // 第一条可见信息的下标
+    pub visible_from: usize,
+    pub limit: usize,
+}
+
+impl SearchIndex {
+    // 重建房间的索引
+    pub fn add_room(&mut self, room: &chat::Room) {
+        self.rooms.insert(room.name.clone(), HashMap::new());
+        for index in 0..room.messages.len() {
+            self.add(&room.name, index, &room.messages[index]);
+        }
+    }
+
+    pub fn rename_room(&mut self, oldname: &str, newname: &str) {
+        if let Some(words) = self.rooms.remove(oldname) {
+            self.rooms.insert(newname.to_string(), words);
+        }
+    }
+
+    pub fn remove_room(&mut self, roomname: &str) {
+        self.rooms.remove(roomname);
+    }
+
+    // 索引房间中第index条信息的当前内容
+    pub fn add(&mut self, roomname: &str, index: usize, message: &chat::Message) {
+        let Some(words) = self.rooms.get_mut(roomname) else {
+            return;
+        };
+        for word in tokenize(&String::from_utf8_lossy(&message.bytes)) {
+            let postings = words.entry(word).or_default();
+            if let Err(pos) = postings.binary_search(&index) {
+                postings.insert(pos, index);
+            }
+        }
+    }
+
+    // 返回匹配全部查询词的信息，最新的在前
+    pub fn search(&self, room: &chat::Room, query: &SearchQuery) -> Vec<chat::Message> {
+        let terms = tokenize(query.text);
+        let Some(words) = self.rooms.get(&room.name) else {
+            return vec![];
+        };
+        let mut postings = vec![];
+        for term in &terms {
+            match words.get(term) {
+                Some(indices) => postings.push(indices),
+                None => return vec![],
+            }
+        }
+        let Some(shortest) = postings.iter().min_by_key(|p| p.len()) else {
+            return vec![];
+        };
+        shortest.iter().rev()
+            .filter(|index| **index >= query.visible_from && postings.iter().all(|p| p.binary_search(index).is_ok()))
+            .filter_map(|index| room.messages.get(*index))
+            .filter(|m| !m.deleted)
+            .filter(|m| query.author.is_none() || m.author().as_deref() == query.author)
+            .filter(|m| m.time >= query.since && (query.until == 0 || m.time <= query.until))
+            // 信息可能在索引之后被编辑过
+            .filter(|m| {
+                let current = tokenize(&String::from_utf8_lossy(&m.bytes));
+                terms.iter().all(|t| current.contains(t))
+            })
+            .take(query.limit)
+            .cloned()
+            .collect()
+    }
+}
+
+// 按非字母数字字符切分并转为小写，中日韩文字没有空格分隔，每个字单独作为一个词
+pub fn tokenize(text: &str) -> Vec<String> {
+    let mut words = vec![];
+    let mut word = String::new();
+    for c in text.chars() {
+        if is_cjk(c) {
+            if !word.is_empty() {
+                words.push(std::mem::take(&mut word));
+            }
+            words.push(c.to_string());
+        } else if c.is_alphanumeric() {
+            word.extend(c.to_lowercase());
+        } else if !word.is_empty() {
+            words.push(std::mem::take(&mut word));
+        }
+    }
+    if !word.is_empty() {
+        words.push(word);
+    }
+    words.sort();
+    words.dedup();
+    words
+}
+
+fn is_cjk(c: char) -> bool {
+    matches!(c as u32, 0x3040..=0x30ff | 0x3400..=0x4dbf | 0x4e00..=0x9fff | 0xac00..=0xd7af | 0xf900..=0xfaff)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn message(author: &str, text: &str, time: u64) -> chat::Message {
+        chat::Message {
+            bytes: text.as_bytes().to_vec(),
+            client: Some(chat::Client {
+                user: Some(chat::User { name: author.to_string(), ..Default::default() }),
+                ..Default::default()
+            }),
+            time,
+            ..Default::default()
+        }
+    }
+
+    fn texts(messages: &[chat::Message]) -> Vec<String> {
+        messages.iter().map(|m| String::from_utf8(m.bytes.clone()).unwrap()).collect()
+    }
+
+    #[test]
+    fn tokenize_words() {
+        assert_eq!(tokenize("Deploy the NEW build, deploy!"), ["build", "deploy", "new", "the"]);
+        assert_eq!(tokenize("明天deploy吗"), ["deploy", "吗", "天", "明"]);
+    }
+
+    #[test]
+    fn search_room() {
+        let mut room = chat::Room { name: "r1".to_string(), ..Default::default() };
+        room.messages.push(message("alice", "release is out", 100));
+        room.messages.push(message("bob", "Release notes?", 200));
+        room.messages.push(message("alice", "release notes are in the wiki", 300));
+        let mut index = SearchIndex::default();
+        index.add_room(&room);
+        let query = |text, author, since, until, visible_from| SearchQuery { text, author, since, until, visible_from, limit: 10 };
+
+        assert_eq!(texts(&index.search(&room, &query("release notes", None, 0, 0, 0))),
+            ["release notes are in the wiki", "Release notes?"]);
+        assert_eq!(texts(&index.search(&room, &query("release", Some("alice"), 0, 0, 0))),
+            ["release notes are in the wiki", "release is out"]);
+        assert_eq!(texts(&index.search(&room, &query("release", None, 150, 250, 0))), ["Release notes?"]);
+        assert_eq!(index.search(&room, &query("release", None, 0, 0, 2)).len(), 1);
+        assert!(index.search(&room, &query("nothing", None, 0, 0, 0)).is_empty());
+        assert!(index.search(&room, &query("", None, 0, 0, 0)).is_empty());
+
+        // edits are searchable and the old words no longer match
+        room.messages[0].bytes = b"hotfix is out".to_vec();
+        index.add("r1", 0, &room.messages[0]);
+        assert_eq!(texts(&index.search(&room, &query("hotfix", None, 0, 0, 0))), ["hotfix is out"]);
+        assert_eq!(index.search(&room, &query("release", None, 0, 0, 0)).len(), 2);
+        room.messages[1].deleted = true;
+        assert_eq!(index.search(&room, &query("notes", None, 0, 0, 0)).len(), 1);
+    }
+}
@@ -1,162 +1,691 @@
 #![allow(unused_variables)]
 
-use tonic::{Request, Response, Status};
+use tonic::{Request, Response, Status, Streaming};
 use std::sync::RwLock;
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::Arc;
 use std::collections::HashMap;
-use std::collections::HashSet;
+use tokio::sync::broadcast;
+use tokio::sync::mpsc;
+use tokio::sync::watch;
+use tokio_stream::wrappers::ReceiverStream;
+use tokio_stream::{Stream, StreamExt};
 use crate::chat;
 use crate::chat::chat_server::Chat;
 use crate::common;
+use crate::server::session;
+use crate::server::password;
+use crate::server::storage::Storage;
+use crate::server::config::Config;
+use crate::server::tls;
+use crate::server::presence::Presence;
+use crate::server::blob::BlobStore;
+use crate::server::search::{SearchIndex, SearchQuery};
+use crate::server::ratelimit::RateLimiter;
 
-#[derive(Default)]
-pub struct Config {
-    pub addr: String,
-    pub datapath: String,
-}
-
-impl Config {
-    pub fn read_file(&mut self, path: &str) {
-        let content = String::from_utf8(std::fs::read(path).unwrap()).unwrap(); 
-        let lines: Vec<&str> = content.split_whitespace().collect();
-        self.addr = lines[0].to_string();
-        self.datapath = lines[1].to_string();
-    }
-}
+// 每个房间广播通道可缓存的信息条数，订阅者落后超过此数会丢信息
+const BROADCAST_CAPACITY: usize = 1024;
+// 检查在线状态超时的间隔
+const PRESENCE_SWEEP: std::time::Duration = std::time::Duration::from_millis(1000);
+// 表情的最大字节数，足够容纳带修饰符的组合表情
+const MAX_EMOJI_LEN: usize = 32;
+// 邀请码的默认和最长有效期
+const DEFAULT_INVITE_TTL_SECS: u64 = 24 * 3600;
+const MAX_INVITE_TTL_SECS: u64 = 30 * 24 * 3600;
+// 一个邀请码最多可以使用的次数
+const MAX_INVITE_USES: u32 = 1000;
 
-#[derive(Default)]
-pub struct MyChatServer {
-    state: RwLock<ServerState>,
+pub struct MyChatServer<S: Storage> {
+    // shared with the presence task
+    state: Arc<RwLock<ServerState>>,
     pub config: Config,
+    // shared with the SessionInterceptor installed in front of the service
+    pub sessions: session::SessionStore,
+    storage: S,
+    // uploaded files referenced by messages
+    blobs: BlobStore,
+    // set once the server starts shutting down, observed by subscriptions and the presence task
+    shutdown: watch::Sender<bool>,
+    presence_task: Mutex<Option<tokio::task::JoinHandle<()>>>,
+    flushed: AtomicBool,
 }
 
-#[derive(Default)]
 pub struct ServerState {
     rooms: Vec<RwLock<chat::Room>>,
     users: Vec<RwLock<chat::User>>,
-    // map roomname to a bunch of online users
-    onlinemap: Arc<RwLock<HashMap<String, HashSet<String>>>>,
-    // note the latest time of user action at room
-    clientuptime: Arc<RwLock<HashMap<String, u64>>>,
-    uptime: Arc<RwLock<u64>>,
+    // online/away state of every (room, user)
+    presence: RwLock<Presence>,
+    // full-text index of every room, rebuilt when the rooms are loaded
+    index: RwLock<SearchIndex>,
+    // map roomname to the fan-out channel of its events
+    broadcasts: HashMap<String, broadcast::Sender<chat::RoomEvent>>,
+    // token buckets of senders and rooms
+    limiter: RateLimiter,
+}
+
+impl ServerState {
+    fn add_room(&mut self, room: chat::Room) {
+        self.presence.write().unwrap().add_room(&room.name);
+        self.index.write().unwrap().add_room(&room);
+        self.broadcasts.insert(room.name.clone(), broadcast::channel(BROADCAST_CAPACITY).0);
+        self.rooms.push(RwLock::new(room));
+    }
+
+    fn publish(&self, roomname: &str, event: chat::room_event::Event) {
+        if let Some(sender) = self.broadcasts.get(roomname) {
+            // 没有订阅者时send会返回错误，忽略即可
+            let _ = sender.send(chat::RoomEvent { event: Some(event) });
+        }
+    }
+
+    fn publish_presence(&self, change: Option<chat::PresenceChange>) {
+        if let Some(change) = change {
+            log::info!("client [{}] is {:?} in room[{}]", change.username, change.state(), change.roomname);
+            let roomname = change.roomname.clone();
+            self.publish(&roomname, chat::room_event::Event::Presence(change));
+        }
+    }
+
+    fn summary(&self, room: &chat::Room) -> chat::RoomSummary {
+        let presence = self.presence.read().unwrap();
+        room.summary(
+            presence.users(&room.name, chat::PresenceState::Online),
+            presence.users(&room.name, chat::PresenceState::Away))
+    }
+
+    // room为None表示房间oldname已被删除
+    fn publish_room_change(&self, oldname: &str, by: &str, room: Option<&chat::Room>) {
+        let roomname = room.map(|r| r.name.as_str()).unwrap_or(oldname);
+        self.publish(roomname, chat::room_event::Event::Room(chat::RoomChange {
+            roomname: oldname.to_string(),
+            by: by.to_string(),
+            room: room.map(|r| self.summary(r)),
+            deleted: room.is_none(),
+            time: common::now_milli_seconds(),
+        }));
+    }
+
+    // 广播通道、在线状态和索引跟随房间改名，订阅者不受影响
+    fn rename_room(&mut self, oldname: &str, newname: &str) {
+        if let Some(sender) = self.broadcasts.remove(oldname) {
+            self.broadcasts.insert(newname.to_string(), sender);
+        }
+        self.presence.write().unwrap().rename_room(oldname, newname);
+        self.index.write().unwrap().rename_room(oldname, newname);
+        self.limiter.rename_room(oldname, newname);
+    }
+
+    // 关闭广播通道，订阅者收完已发出的事件后结束
+    fn remove_room(&mut self, roomname: &str) {
+        self.broadcasts.remove(roomname);
+        self.presence.write().unwrap().remove_room(roomname);
+        self.index.write().unwrap().remove_room(roomname);
+        self.rooms.retain_mut(|r| r.get_mut().unwrap().name != roomname);
+    }
 }
 
-impl MyChatServer {
+impl<S: Storage> MyChatServer<S> {
+    pub fn new(config: Config, storage: S) -> Self {
+        MyChatServer {
+            state: Arc::new(RwLock::new(ServerState {
+                rooms: vec![],
+                users: vec![],
+                presence: RwLock::new(Presence::new(config.away_timeout_ms, config.presence_timeout_ms)),
+                index: RwLock::new(SearchIndex::default()),
+                broadcasts: HashMap::new(),
+                limiter: RateLimiter::new(&config.limits),
+            })),
+            blobs: BlobStore::new(&config.datapath),
+            config,
+            sessions: session::SessionStore::default(),
+            storage,
+            shutdown: watch::channel(false).0,
+            presence_task: Mutex::new(None),
+            flushed: AtomicBool::new(false),
+        }
+    }
+
     pub fn init(&self) -> Result<(), Box<dyn std::error::Error>> {
         let mut state = self.state.write().unwrap();
-        {
-            std::fs::create_dir_all(&self.config.datapath)?;
-            // for simplisity, load all roominfos
-            let readdir = std::fs::read_dir(&self.config.datapath)?;
-            for diri in readdir {
-                let entry = diri?;
-                let path = entry.path();
-                let pathstr = path.to_str().unwrap().to_string();
-                if pathstr.contains("room") {
-                    state.rooms.push(RwLock::new(chat::Room::from_file(&pathstr).unwrap()));
-                    state.onlinemap.write().unwrap().insert(
-                        pathstr[self.config.datapath.len()+6..].to_string(), HashSet::new());
-                }
-                if pathstr.contains("user") {
-                    state.users.push(RwLock::new(chat::User::from_file(&pathstr).unwrap()));
+        for mut room in self.storage.load_rooms()? {
+            if room.clear_passwords() {
+                log::info!("clear legacy user passwords stored in room [{}]", room.name);
+                self.storage.compact_room(&room)?;
+            }
+            let assigned = room.assign_sequence();
+            if !assigned.is_empty() {
+                log::info!("assign id and seq to {} legacy messages of room [{}]", assigned.len(), room.name);
+                for index in assigned {
+                    self.storage.update_message(&room, index)?;
                 }
             }
+            state.add_room(room);
+        }
+        for mut user in self.storage.load_users()? {
+            // upgrade legacy plaintext password in place
+            if !password::is_hashed(&user.password) {
+                log::info!("hash legacy password of user [{}]", user.name);
+                user.password = password::hash(&user.password);
+                self.storage.save_user(&user)?;
+            }
+            state.users.push(RwLock::new(user));
         }
-        *state.uptime.write().unwrap() = common::now_milli_seconds();
-        let uptime = Arc::clone(&state.uptime);
-        let onlinemap = Arc::clone(&state.onlinemap);
-        let clientuptime = Arc::clone(&state.clientuptime);
         drop(state);
+        self.blobs.clean()?;
 
-        std::thread::spawn(move || loop {
-            let uptimeval = common::now_milli_seconds();
-            *uptime.write().unwrap() = uptimeval;
-
-            let mut om_writer = onlinemap.write().unwrap();
-            for (_roomname, onlineset) in om_writer.iter_mut() {
-                if onlineset.is_empty() {
-                    continue;
+        let state = Arc::clone(&self.state);
+        let mut stopping = self.shutdown.subscribe();
+        let handle = tokio::spawn(async move {
+            let mut interval = tokio::time::interval(PRESENCE_SWEEP);
+            loop {
+                tokio::select! {
+                    _ = interval.tick() => {},
+                    _ = async { let _ = stopping.wait_for(|stop| *stop).await; } => break,
                 }
-                let cu_reader = clientuptime.read().unwrap(); 
-                for (username, t) in cu_reader.iter() {
-                    if uptimeval > *t && uptimeval - *t > 5000 {
-                        // println!("{:?} remove {}", onlineset, username);
-                        onlineset.remove(username);
-                    }
+                let state = state.read().unwrap();
+                let now = common::now_milli_seconds();
+                let changes = state.presence.write().unwrap().sweep(now);
+                for change in changes {
+                    state.publish_presence(Some(change));
                 }
+                state.limiter.sweep(now);
             }
-            drop(om_writer);
-            std::thread::sleep(std::time::Duration::from_millis(1000));    
         });
+        *self.presence_task.lock().unwrap() = Some(handle);
         Ok(())
     }
+
+    // 通知所有订阅者服务器即将关闭并结束订阅流，之后不再接受新的订阅
+    pub fn begin_shutdown(&self) {
+        self.shutdown.send_replace(true);
+    }
+
+    // 停止后台任务并写入全部数据，服务停止后调用
+    pub fn shutdown(&self) {
+        self.begin_shutdown();
+        if let Some(handle) = self.presence_task.lock().unwrap().take() {
+            handle.abort();
+        }
+        self.serialize();
+    }
 }
 
-impl Drop for MyChatServer {
+impl<S: Storage> Drop for MyChatServer<S> {
     // serialize all rooms
     fn drop(&mut self) {
         self.serialize();
     }
 }
 
-impl MyChatServer {
+impl<S: Storage> MyChatServer<S> {
+    // 只在关闭时写一次
     fn serialize(&self) {
-        let datapath = &self.config.datapath;
-        for room in self.state.read().unwrap().rooms.iter() {
+        if self.flushed.swap(true, Ordering::SeqCst) {
+            return;
+        }
+        log::info!("flush all rooms and users");
+        let state = self.state.read().unwrap();
+        for room in state.rooms.iter() {
+            let room = room.read().unwrap();
+            if let Err(e) = self.storage.compact_room(&room) {
+                log::error!("compact room[{}]: {}", room.name, e);
+            }
+        }
+        for user in state.users.iter() {
+            self.save_user(&user.read().unwrap());
+        }
+    }
+
+    fn save_room(&self, room: &chat::Room) {
+        if let Err(e) = self.storage.save_room(room) {
+            log::error!("save room[{}]: {}", room.name, e);
+        }
+    }
+
+    fn save_user(&self, user: &chat::User) {
+        if let Err(e) = self.storage.save_user(user) {
+            log::error!("save user[{}]: {}", user.name, e);
+        }
+    }
+
+    fn append_message(&self, room: &chat::Room) {
+        if let Err(e) = self.storage.append_message(room) {
+            log::error!("append message to room[{}]: {}", room.name, e);
+        }
+    }
+
+    // 分配id和seq后追加信息、推送给订阅者并持久化，调用者持有房间写锁
+    fn post_message(&self, state: &ServerState, room: &mut chat::Room, mut message: chat::Message) -> Result<(), Status> {
+        self.check_message_size(&message.bytes)?;
+        // 只能回复自己看得到的信息
+        let username = message.author().unwrap_or_default();
+        if !message.reply_to.is_empty() && !room.visible_messages(&username).iter().any(|m| m.id == message.reply_to) {
+            return Err(Status::not_found(format!("reply to message {} not exist", message.reply_to)));
+        }
+        // 引用的文件必须已经上传，大小以服务器上的为准
+        if let Some(blob) = message.blob.as_mut() {
+            blob.size = self.blobs.size(&blob.hash)
+                .ok_or_else(|| Status::not_found(format!("blob {} not uploaded", blob.hash)))?;
+        }
+        let now = common::now_milli_seconds();
+        if let Err(wait) = state.limiter.acquire(&username, &room.name, now) {
+            log::warn!("client [{}] send too fast in room[{}]", username, room.name);
+            return Err(rate_limited(wait));
+        }
+        message.id = common::random_hex(16);
+        message.seq = room.last_seq() + 1;
+        message.time = now;
+        log::info!("add message[{}] to room[{}]",
+            String::from_utf8_lossy(&message.bytes),
+            room.name);
+        room.messages.push(message.clone());
+        state.index.write().unwrap().add(&room.name, room.messages.len() - 1, &message);
+        // 在持有房间写锁时广播，保证订阅者收到的顺序与messages一致
+        state.publish(&room.name, chat::room_event::Event::Message(message));
+        self.append_message(room);
+        Ok(())
+    }
+
+    fn check_message_size(&self, bytes: &[u8]) -> Result<(), Status> {
+        let limit = self.config.limits.max_message_bytes as usize;
+        if bytes.len() > limit {
+            return Err(Status::invalid_argument(format!("message is {} bytes, the limit is {} bytes", bytes.len(), limit)));
+        }
+        Ok(())
+    }
+
+    // 推送并保存房间中被修改的第index条信息，调用者持有房间写锁
+    fn update_posted(&self, state: &ServerState, room: &chat::Room, index: usize) {
+        state.index.write().unwrap().add(&room.name, index, &room.messages[index]);
+        state.publish(&room.name, chat::room_event::Event::Updated(room.messages[index].clone()));
+        if let Err(e) = self.storage.update_message(room, index) {
+            log::error!("update message of room[{}]: {}", room.name, e);
+        }
+    }
+
+    // 接收上传流写入文件存储，超过大小限制时放弃已写入的部分
+    async fn receive_upload(&self, username: &str, mut stream: impl Stream<Item = Result<chat::UploadRequest, Status>> + Unpin)
+        -> Result<chat::BlobRef, Status> {
+        let limit = self.config.max_upload_bytes;
+        let too_large = || Status::invalid_argument(format!("file exceeds the upload limit of {} bytes", limit));
+        let mut writer = self.blobs.writer().map_err(|e| {
+            log::error!("create blob: {}", e);
+            Status::internal("can not store the file")
+        })?;
+        let mut filename = None;
+        while let Some(chunk) = stream.next().await {
+            let chunk = chunk?;
+            if filename.is_none() {
+                if chunk.size > limit {
+                    return Err(too_large());
+                }
+                filename = Some(chunk.filename);
+            }
+            if writer.size() + chunk.data.len() as u64 > limit {
+                return Err(too_large());
+            }
+            writer.write(&chunk.data).map_err(|e| {
+                log::error!("write blob: {}", e);
+                Status::internal("can not store the file")
+            })?;
+        }
+        if writer.size() == 0 {
+            return Err(Status::invalid_argument("file is empty"));
+        }
+        let size = writer.size();
+        let hash = writer.finish().map_err(|e| {
+            log::error!("finish blob: {}", e);
+            Status::internal("can not store the file")
+        })?;
+        log::info!("client [{}] upload blob {} of {} bytes", username, hash, size);
+        Ok(chat::BlobRef {
+            hash,
+            size,
+            filename: filename.unwrap_or_default(),
+        })
+    }
+
+    // kick、ban、unban、promote和demote共用，成功后保存房间并通知成员
+    fn moderate(&self, request: Request<chat::ModerateRequest>, action: Moderation) -> Result<Response<chat::ServerResponse>, Status> {
+        let user = session::authenticate(&request)?;
+        let mut req = request.into_inner();
+        bind_client(&mut req.client, &user)?;
+        if req.roomname.is_empty() || req.username.is_empty() {
+            return Err(Status::invalid_argument("roomname or username is empty"));
+        }
+        if req.username == user.username {
+            return Err(Status::invalid_argument("can not moderate yourself"));
+        }
+
+        let state = self.state.read().unwrap();
+        let room = state.rooms.iter().find(|r| r.read().unwrap().name == req.roomname)
+            .ok_or_else(|| Status::invalid_argument("room is none"))?;
+        let mut room_writer = room.write().unwrap();
+        if room_writer.direct {
+            return Err(Status::failed_precondition("direct conversations have no roles"));
+        }
+        let (me, target) = (&user.username, &req.username);
+        let target_role = room_writer.role(target);
+        let role = match action {
+            Moderation::Kick | Moderation::Ban | Moderation::Unban => {
+                if !room_writer.can_moderate(me, target) {
+                    return Err(Status::permission_denied(format!("you can not moderate {} in this room", target)));
+                }
+                match (action, target_role) {
+                    (Moderation::Kick, chat::Role::NotMember | chat::Role::Banned) =>
+                        return Err(Status::failed_precondition(format!("{} is not a member", target))),
+                    (Moderation::Kick, _) => {
+                        room_writer.remove_member(target);
+                        chat::Role::NotMember
+                    },
+                    (Moderation::Ban, _) => {
+                        room_writer.remove_member(target);
+                        room_writer.roles.insert(target.clone(), chat::Role::Banned as i32);
+                        chat::Role::Banned
+                    },
+                    (_, chat::Role::Banned) => {
+                        room_writer.roles.remove(target);
+                        chat::Role::NotMember
+                    },
+                    _ => return Err(Status::failed_precondition(format!("{} is not banned", target))),
+                }
+            },
+            Moderation::Promote | Moderation::Demote => {
+                if room_writer.role(me) != chat::Role::Owner {
+                    return Err(Status::permission_denied("only the room owner can change roles"));
+                }
+                match (action, target_role) {
+                    (Moderation::Promote, chat::Role::Member) => {
+                        room_writer.roles.insert(target.clone(), chat::Role::Moderator as i32);
+                        chat::Role::Moderator
+                    },
+                    (Moderation::Demote, chat::Role::Moderator) => {
+                        room_writer.roles.remove(target);
+                        chat::Role::Member
+                    },
+                    (Moderation::Promote, _) => return Err(Status::failed_precondition(format!("{} is not a member", target))),
+                    _ => return Err(Status::failed_precondition(format!("{} is not a moderator", target))),
+                }
+            },
+        };
+        log::info!("client [{}] {:?} [{}] in room[{}]", me, action, target, req.roomname);
+        self.save_room(&room_writer);
+        let now = common::now_milli_seconds();
+        state.publish(&req.roomname, chat::room_event::Event::Member(chat::MemberChange {
+            roomname: req.roomname.clone(),
+            username: target.clone(),
+            role: role as i32,
+            by: me.clone(),
+            time: now,
+        }));
+        drop(room_writer);
+        if matches!(role, chat::Role::NotMember | chat::Role::Banned) {
+            state.publish_presence(state.presence.write().unwrap().leave(&req.roomname, target, now));
+        }
+        Ok(Response::new(chat::ServerResponse::default()))
+    }
+
+    // add_reaction和remove_reaction共用，apply返回信息是否有变化
+    fn react(&self, request: Request<chat::ReactionRequest>, apply: fn(&mut chat::Message, &str, &str) -> bool)
+        -> Result<Response<chat::ServerResponse>, Status> {
+        let user = session::authenticate(&request)?;
+        let mut req = request.into_inner();
+        bind_client(&mut req.client, &user)?;
+        if req.roomname.is_empty() || req.id.is_empty() {
+            return Err(Status::invalid_argument("roomname or message id is empty"));
+        }
+        if req.emoji.is_empty() || req.emoji.len() > MAX_EMOJI_LEN || req.emoji.chars().any(char::is_whitespace) {
+            return Err(Status::invalid_argument("invalid emoji"));
+        }
+
+        let state = self.state.read().unwrap();
+        let room = state.rooms.iter().find(|r| r.read().unwrap().name == req.roomname)
+            .ok_or_else(|| Status::invalid_argument("room is none"))?;
+        let mut room_writer = room.write().unwrap();
+        if !common::client_in_room_w(req.client.as_ref().unwrap(), &room_writer) {
+            let msg = format!("client not exist in room {}", req.roomname);
+            log::error!("{}", msg);
+            return Err(Status::invalid_argument(msg));
+        }
+        // 只能回应自己看得到的信息
+        let visible_from = room_writer.visible_from(&user.username);
+        let index = room_writer.message_index(&req.id)
+            .filter(|index| *index >= visible_from)
+            .ok_or_else(|| Status::not_found(format!("message {} not exist", req.id)))?;
+        let message = &mut room_writer.messages[index];
+        if message.deleted {
+            return Err(Status::failed_precondition("message is deleted"));
+        }
+        if apply(message, &req.emoji, &user.username) {
+            log::info!("client [{}] react {} to message[{}] of room[{}]", user.username, req.emoji, message.seq, req.roomname);
+            self.update_posted(&state, &room_writer, index);
+        }
+
+        Ok(Response::new(chat::ServerResponse {
+            messages: vec![room_writer.messages[index].clone()],
+            ..Default::default()
+        }))
+    }
+
+    // 返回两个用户之间的私聊房间，不存在时创建
+    fn direct_room(&self, me: &chat::Client, peer: &str) -> Result<String, Status> {
+        let username = me.username();
+        let roomname = common::direct_roomname(&username, peer);
+        let state = self.state.read().unwrap();
+        if !state.users.iter().any(|u| u.read().unwrap().name == peer) {
+            return Err(Status::not_found(format!("user {} not exist", peer)));
+        }
+        if let Some(room) = state.rooms.iter().find(|r| r.read().unwrap().name == roomname) {
             let room_reader = room.read().unwrap();
-            let _ = room_reader.to_file(&format!("{}/room_{}", datapath, &room_reader.name));
+            if !room_reader.direct || room_reader.peer(&username).as_deref() != Some(peer) {
+                return Err(Status::failed_precondition(format!("room {} is not a direct conversation", roomname)));
+            }
+            return Ok(roomname);
         }
-        for user in self.state.read().unwrap().users.iter() {
-            let user_reader = user.read().unwrap();
-            let _ = user_reader.to_file(&format!("{}/user_{}", datapath, user_reader.name));
+        drop(state);
+
+        let mut state_writer = self.state.write().unwrap();
+        // created concurrently by the peer
+        if state_writer.rooms.iter().any(|r| r.read().unwrap().name == roomname) {
+            return Ok(roomname);
         }
+        let peer_client = chat::Client {
+            user: Some(chat::User { name: peer.to_string(), ..Default::default() }),
+            device: None,
+        };
+        let room = chat::Room {
+            name: roomname.clone(),
+            created_time: common::now_milli_seconds(),
+            history_visible: true,
+            clients: vec![me.clone(), peer_client],
+            join_points: HashMap::from([(username, 0), (peer.to_string(), 0)]),
+            direct: true,
+            ..Default::default()
+        };
+        log::info!("create direct conversation [{}]", roomname);
+        self.save_room(&room);
+        state_writer.add_room(room);
+        Ok(roomname)
+    }
+}
+
+// 房间管理操作
+#[derive(Debug, Clone, Copy)]
+enum Moderation {
+    Kick,
+    Ban,
+    Unban,
+    Promote,
+    Demote,
+}
+
+// 房间生命周期的操作只有房主可以执行，私聊房间不支持
+fn owner_only(room: &chat::Room, username: &str) -> Result<(), Status> {
+    if room.direct {
+        return Err(Status::failed_precondition("direct conversations can not be managed"));
+    }
+    if !room.is_owner(username) {
+        return Err(Status::permission_denied(format!("only the owner can manage room {}", room.name)));
+    }
+    Ok(())
+}
+
+// 修改和删除信息前先确认仍是房间成员，被封禁的用户直接拒绝
+fn member_only(room: &chat::Room, username: &str) -> Result<(), Status> {
+    if room.is_banned(username) {
+        return Err(banned(&room.name));
+    }
+    if !room.is_member(username) {
+        return Err(Status::permission_denied(format!("not a member of room {}", room.name)));
+    }
+    Ok(())
+}
+
+// 房主和管理员可以管理邀请码
+fn invite_manager(room: &chat::Room, username: &str) -> Result<(), Status> {
+    if room.direct {
+        return Err(Status::failed_precondition("direct conversations have no invites"));
+    }
+    if !matches!(room.role(username), chat::Role::Owner | chat::Role::Moderator) {
+        return Err(Status::permission_denied(format!("only the owner and moderators can manage invites of room {}", room.name)));
     }
+    Ok(())
+}
+
+// 发送太快，wait毫秒后可以重试
+fn rate_limited(wait: u64) -> Status {
+    let mut metadata = tonic::metadata::MetadataMap::new();
+    metadata.insert(common::RETRY_AFTER_METADATA, wait.into());
+    Status::with_metadata(tonic::Code::ResourceExhausted,
+        format!("sending too fast, retry after {:.1}s", wait as f64 / 1000.0), metadata)
+}
+
+// 用户名和房间名是数据文件名的一部分，不能跳出数据目录，也不能和临时文件混淆
+fn validate_name(kind: &str, name: &str) -> Result<(), Status> {
+    if name.is_empty() {
+        return Err(Status::invalid_argument(format!("{} is empty", kind)));
+    }
+    if name.starts_with('.') || name.contains("..") || name.contains(['/', '\\', '\0']) {
+        return Err(Status::invalid_argument(format!("{} must not start with '.' or contain '..', '/' or '\\'", kind)));
+    }
+    Ok(())
+}
+
+// 私聊房间名用':'连接两个用户名，用户名中不能有':'，否则不同的两对用户会得到同一个房间名
+fn validate_username(username: &str) -> Result<(), Status> {
+    validate_name("username", username)?;
+    if username.contains(':') {
+        return Err(Status::invalid_argument("username must not contain ':'"));
+    }
+    Ok(())
+}
+
+// 新建和改名时房间名的检查，私聊房间的前缀只能由服务器使用
+fn validate_room_name(roomname: &str) -> Result<(), Status> {
+    validate_name("roomname", roomname)?;
+    if roomname.starts_with(common::DIRECT_PREFIX) {
+        return Err(Status::invalid_argument(format!("room name must not start with {}", common::DIRECT_PREFIX)));
+    }
+    Ok(())
+}
+
+fn banned(roomname: &str) -> Status {
+    Status::permission_denied(format!("you are banned from room {}", roomname))
+}
+
+// 房间没有设置密码时任何人都可以进入
+fn room_password_match(room: &chat::Room, password: Option<&str>) -> bool {
+    match room.password.as_deref() {
+        None | Some("") => true,
+        Some(expected) => password == Some(expected),
+    }
+}
+
+// 请求中的client以会话用户为准，并且不再保留客户端发来的密码
+fn bind_client(client: &mut Option<chat::Client>, user: &session::SessionUser) -> Result<(), Status> {
+    let client = match client {
+        Some(c) => c,
+        None => {
+            log::error!("client is none");
+            return Err(Status::invalid_argument("client is none"));
+        },
+    };
+    let gender = client.user.as_ref().and_then(|u| u.gender);
+    client.user = Some(chat::User {
+        name: user.username.clone(),
+        password: String::new(),
+        gender,
+    });
+    if let Some(device) = &user.device {
+        client.device = Some(chat::Device { serial_number: device.clone() });
+    }
+    Ok(())
 }
 
 #[tonic::async_trait]
-impl Chat for MyChatServer {
+impl<S: Storage> Chat for MyChatServer<S> {
     // now signup and login is conbined together
     async fn signup(
         &self,
         request: Request<chat::UserSignupRequest>
     ) -> Result<Response<chat::ServerResponse>, Status> {
+        let device = tls::peer_device(&request)?;
         let req = request.into_inner();
-        let username = &req.client.as_ref().unwrap().user.as_ref().unwrap().name;
-        if req.client.is_none() {
-            log::error!("client is none");
-            return Err(Status::invalid_argument("client is none"));
+        let client = req.client.as_ref().ok_or_else(|| Status::invalid_argument("client is none"))?;
+        let username = &client.user.as_ref().ok_or_else(|| Status::invalid_argument("user is none"))?.name;
+        // 设备的序列号必须与客户端证书一致
+        if let Some(device) = &device {
+            let claimed = client.device.as_ref().map(|d| d.serial_number.as_str());
+            if claimed != Some(device.as_str()) {
+                log::error!("user [{}] claims device {:?} with certificate of {}", username, claimed, device);
+                return Err(Status::permission_denied("device serial number does not match the client certificate"));
+            }
         }
         if req.password.is_empty() { // create room
             return Err(Status::invalid_argument("password is empty"));
         }
-        log::info!("try signup: username: {}, password: {}", username, &req.password);
+        log::info!("try signup: username: {}", username);
 
-        let state = self.state.read().unwrap();
-        let user = state.users.iter().find(|u| {
-            let user_reader = u.read().unwrap();
-            user_reader.name == *username
-        });
+        let stored = self.state.read().unwrap().users.iter()
+            .map(|u| u.read().unwrap())
+            .find(|user_reader| user_reader.name == *username)
+            .map(|user_reader| user_reader.password.clone());
 
+        // argon2 is deliberately slow, keep it off the async workers
+        let plain = req.password.clone();
         let mut response = chat::ServerResponse::default();
-        // user not exist, signup
-        if user.is_none() {
-            drop(state);
-            let mut state_writer = self.state.write().unwrap();
-            state_writer.users.push(RwLock::new(chat::User{
-                name: username.clone(),
-                gender: Some(1),
-                password: req.password,
-            }));
-        } else {
+        match stored {
+            // user not exist, signup
+            None => {
+                validate_username(username)?;
+                let hashed = tokio::task::spawn_blocking(move || password::hash(&plain)).await
+                    .map_err(|e| Status::internal(e.to_string()))?;
+                let mut state_writer = self.state.write().unwrap();
+                if state_writer.users.iter().any(|u| u.read().unwrap().name == *username) {
+                    return Err(Status::aborted("user signed up concurrently, try again"));
+                }
+                let user = chat::User{
+                    name: username.clone(),
+                    gender: Some(1),
+                    password: hashed,
+                };
+                self.save_user(&user);
+                state_writer.users.push(RwLock::new(user));
+            },
             // sign in
             // check password
-            if user.unwrap().read().unwrap().password != req.password {
-                response.code = chat::ResponseCode::PasswordWrong as i32;
-            }
+            Some(stored) => {
+                let ok = tokio::task::spawn_blocking(move || password::verify(&plain, &stored)).await
+                    .map_err(|e| Status::internal(e.to_string()))?;
+                if !ok {
+                    response.code = chat::ResponseCode::PasswordWrong as i32;
+                }
+            },
+        }
+        if response.code == chat::ResponseCode::Ok as i32 {
+            response.token = self.sessions.create(username, device.as_deref());
         }
-
-        // TODO more efficient serialize
-        self.serialize();
         
         Ok(Response::new(response))
     }
@@ -165,12 +694,10 @@ impl Chat for MyChatServer {
         &self,
         request: Request<chat::JoinRequest>
     ) -> Result<Response<chat::ServerResponse>, Status> {
-        let req = request.into_inner();
-        let username = &req.client.as_ref().unwrap().user.as_ref().unwrap().name;
-        if req.client.is_none() {
-            log::error!("client is none");
-            return Err(Status::invalid_argument("client is none"));
-        }
+        let user = session::authenticate(&request)?;
+        let mut req = request.into_inner();
+        bind_client(&mut req.client, &user)?;
+        let username = &user.username;
         if req.roomname.is_empty() { // create room
             log::error!("roomname is none");
             return Err(Status::invalid_argument("roomname is none"));
@@ -189,13 +716,45 @@ impl Chat for MyChatServer {
         } 
 
         let mut room_writer = room.unwrap().write().unwrap();
-        if !room_writer.clients.contains(&(req.client.clone().unwrap())) {
+        if room_writer.is_banned(username) {
+            return Err(banned(&roomname));
+        }
+        // 只有第一次进入房间时需要房间密码，之后凭成员身份进入
+        let new_member = !common::client_in_room_w(req.client.as_ref().unwrap(), &room_writer);
+        if new_member && room_writer.direct {
+            return Err(Status::permission_denied("direct conversation of other users"));
+        }
+        // 有效的邀请码代替房间密码
+        let invited = match req.invite_code.as_deref() {
+            Some(code) if new_member => {
+                if !room_writer.use_invite(code, common::now_milli_seconds()) {
+                    return Err(Status::permission_denied("invite code is invalid or expired"));
+                }
+                true
+            },
+            _ => false,
+        };
+        if new_member && !invited && room_writer.invite_only {
+            return Err(Status::permission_denied(format!("room {} is invite only", roomname)));
+        }
+        if new_member {
+            if !invited && !room_password_match(&room_writer, req.room_password.as_deref()) {
+                log::info!("client [{}] give wrong password of room[{}]", username, roomname);
+                response.code = chat::ResponseCode::RoomPasswordWrong as i32;
+                return Ok(Response::new(response));
+            }
             room_writer.clients.push(req.client.clone().unwrap());
+            let join_seq = room_writer.last_seq();
+            room_writer.join_points.insert(username.clone(), join_seq);
+            // remember the admitted member
+            self.save_room(&room_writer);
         }
-        response.messages = room_writer.messages.clone();
+        let (messages, more_history) = room_writer.visible_before(username, 0, self.config.history.join_limit as usize);
+        response.messages = messages.to_vec();
+        response.more_history = more_history;
+        drop(room_writer);
 
-        let mut map = state.onlinemap.write().unwrap();
-        map.get_mut(&roomname).unwrap().insert(username.clone());
+        state.publish_presence(state.presence.write().unwrap().active(&roomname, username, common::now_milli_seconds()));
 
         Ok(Response::new(response))
 
@@ -206,12 +765,10 @@ impl Chat for MyChatServer {
         request: Request<chat::HeartBeatRequest>
     ) -> Result<Response<chat::ServerResponse>, Status> {
         // log::info!("Got a heartbeat request from {:?}", request.remote_addr());
-        let req = request.into_inner();
-        let username = &req.client.as_ref().unwrap().user.as_ref().unwrap().name;
-        if req.client.is_none() {
-            log::error!("client is none");
-            return Err(Status::invalid_argument("client is none"));
-        }
+        let user = session::authenticate(&request)?;
+        let mut req = request.into_inner();
+        bind_client(&mut req.client, &user)?;
+        let username = &user.username;
         if req.roomname.is_empty() { // create room
             log::error!("roomname is none");
             return Err(Status::invalid_argument("roomname is none"));
@@ -231,15 +788,23 @@ impl Chat for MyChatServer {
 
         // room found, check if client exists in this room
         let room_reader = room.unwrap().read().unwrap();
-        assert!(common::client_in_room(req.client.as_ref().unwrap(), &room_reader));
-        for i in (req.msgnum as usize)..room_reader.messages.len() {
-            response.messages.push(room_reader.messages[i].clone()); 
+        if room_reader.is_banned(username) {
+            return Err(banned(&roomname));
+        }
+        if !common::client_in_room(req.client.as_ref().unwrap(), &room_reader) {
+            let msg = format!("client not exist in room {}", req.roomname);
+            log::error!("{}", msg);
+            return Err(Status::invalid_argument(msg));
+        }
+        // 一次最多补一页，剩下的下次心跳再取
+        for message in room_reader.visible_after(username, req.after_seq).iter().take(self.config.history.page_limit as usize) {
+            response.messages.push(message.clone()); 
             log::info!("client [{}] recv new msg", username);
         }
 
-        // update clientuptime
-        let mut clientuptime = state.clientuptime.write().unwrap();
-        clientuptime.insert(username.clone(), common::now_milli_seconds());
+        drop(room_reader);
+
+        state.publish_presence(state.presence.write().unwrap().touch(&roomname, username, common::now_milli_seconds()));
         Ok(Response::new(response))
     }
 
@@ -248,11 +813,9 @@ impl Chat for MyChatServer {
         request: Request<chat::SendRequest>
     ) -> Result<Response<chat::ServerResponse>, Status> {
         log::info!("Got a send request from {:?}", request.remote_addr());
-        let req = request.into_inner();
-        if req.client.is_none() {
-            log::error!("client is none");
-            return Err(Status::invalid_argument("client is none"));
-        }
+        let user = session::authenticate(&request)?;
+        let mut req = request.into_inner();
+        bind_client(&mut req.client, &user)?;
         if req.message.is_none() {
             log::error!("message is none");
             return Err(Status::invalid_argument("message is none"));
@@ -273,6 +836,9 @@ impl Chat for MyChatServer {
         }
 
         let mut room_writer = room.unwrap().write().unwrap();
+        if room_writer.is_banned(&user.username) {
+            return Err(banned(&req.roomname));
+        }
         let client_exist_in_room = common::client_in_room_w(req.client.as_ref().unwrap(), &room_writer);
 
         if !client_exist_in_room {
@@ -281,45 +847,37 @@ impl Chat for MyChatServer {
             return Err(Status::invalid_argument(msg));
         }
 
-        let message = &req.message.unwrap();
-        log::info!("add message[{}] to room[{}]", 
-            String::from_utf8(message.bytes.clone()).unwrap(), 
-            &req.roomname);
-        room_writer.messages.push(message.clone());
-
+        let mut message = req.message.unwrap();
+        // 信息的发送者以会话为准
+        message.client = req.client.clone();
+        self.post_message(&state, &mut room_writer, message)?;
+        let response = chat::ServerResponse {
+            messages: room_writer.messages.last().cloned().into_iter().collect(),
+            ..Default::default()
+        };
         drop(room_writer);
-        // TODO more efficient serialize
-        self.serialize();
 
-        Ok(Response::new(chat::ServerResponse::default()))
+        state.publish_presence(state.presence.write().unwrap().active(&req.roomname, &user.username, common::now_milli_seconds()));
+
+        Ok(Response::new(response))
     }
 
     async fn getrooms(
         &self, 
         request: Request<chat::GetRoomsRequest>
     ) -> Result<Response<chat::ServerResponse>, Status> {
-        let req = request.into_inner();
-        if req.client.is_none() {
-            log::error!("client is none");
-            return Err(Status::invalid_argument("client is none"));
-        }
+        let user = session::authenticate(&request)?;
+        let mut req = request.into_inner();
+        bind_client(&mut req.client, &user)?;
 
         let state = self.state.read().unwrap();
-        let map = state.onlinemap.write().unwrap();
         let mut response = chat::ServerResponse::default();
         state.rooms.iter().for_each(|x| {
-            let roomname = &x.read().unwrap().name;
-            response.roominfos.push(chat::RoomInfo{
-                name: roomname.to_string(),
-                manner: x.read().unwrap().manner.clone(),
-                online_users: match map.get(roomname) {
-                    Some(v) => { 
-                        v.iter().map(|s_ref| s_ref.clone()).collect()
-                    },
-                    None => vec![],
-                },
-                password: x.read().unwrap().password.clone(),
-            });
+            let room_reader = x.read().unwrap();
+            if room_reader.direct {
+                return;
+            }
+            response.rooms.push(state.summary(&room_reader));
         });
         Ok(Response::new(response))
     }
@@ -328,17 +886,15 @@ impl Chat for MyChatServer {
         &self, 
         request: Request<chat::GetUsersRequest>
     ) -> Result<Response<chat::ServerResponse>, Status> {
-        let req = request.into_inner();
-        if req.client.is_none() {
-            log::error!("client is none");
-            return Err(Status::invalid_argument("client is none"));
-        }
+        let user = session::authenticate(&request)?;
+        let mut req = request.into_inner();
+        bind_client(&mut req.client, &user)?;
 
         let state = self.state.read().unwrap();
         let mut response = chat::ServerResponse::default();
         state.users.iter().for_each(|x| {
             let user = x.read().unwrap();
-            response.users.push(user.clone());
+            response.users.push(user.profile());
         });
         Ok(Response::new(response))
     }
@@ -348,15 +904,10 @@ impl Chat for MyChatServer {
         request: Request<chat::CreateRoomRequest>
     ) -> Result<Response<chat::ServerResponse>, Status> {
         log::info!("create room");
-        let req = request.into_inner();
-        if req.client.is_none() {
-            log::error!("client is none");
-            return Err(Status::invalid_argument("client is none"));
-        }
-        if req.roomname.is_empty() { // create room
-            log::error!("roomname is none");
-            return Err(Status::invalid_argument("roomname is none"));
-        }
+        let user = session::authenticate(&request)?;
+        let mut req = request.into_inner();
+        bind_client(&mut req.client, &user)?;
+        validate_room_name(&req.roomname)?;
 
         let state = self.state.read().unwrap();
         let room = state.rooms.iter().find(|x| {
@@ -364,7 +915,7 @@ impl Chat for MyChatServer {
             room_reader.name == req.roomname.clone()
         });
 
-        if !room.is_none() {
+        if room.is_some() {
             log::error!("create existed room");
             return Err(Status::invalid_argument("create existed room"));
         }
@@ -373,7 +924,11 @@ impl Chat for MyChatServer {
         // unlock the read lock to create write lock 
         drop(state);
         let mut state_writer = self.state.write().unwrap();
-        state_writer.rooms.push(RwLock::new(chat::Room{
+        // created or renamed to concurrently
+        if state_writer.rooms.iter().any(|r| r.read().unwrap().name == req.roomname) {
+            return Err(Status::already_exists(format!("room {} already exists", req.roomname)));
+        }
+        let room = chat::Room{
             created_time: common::now_milli_seconds(),
             history_visible: req.history_visible,
             manner: req.client.clone(),
@@ -381,12 +936,14 @@ impl Chat for MyChatServer {
             clients: vec![req.client.clone().unwrap()],
             name: req.roomname.clone(),
             password: req.password,
-        }));
-        state_writer.onlinemap.write().unwrap().insert(req.roomname.clone(), HashSet::new());
-
-        // TODO more efficient serialize
-        drop(state_writer);
-        self.serialize();
+            join_points: HashMap::from([(user.username.clone(), 0)]),
+            direct: false,
+            roles: HashMap::new(),
+            invite_only: false,
+            invites: vec![],
+        };
+        self.save_room(&room);
+        state_writer.add_room(room);
 
         Ok(Response::new(response))
 
@@ -396,19 +953,2362 @@ impl Chat for MyChatServer {
         &self, 
         request: Request<chat::ExitRoomRequest>
     ) -> Result<Response<chat::ServerResponse>, Status> {
-        let req = request.into_inner();
-        if req.client.is_none() {
-            log::error!("client is none");
-            return Err(Status::invalid_argument("client is none"));
-        }
+        let user = session::authenticate(&request)?;
+        let mut req = request.into_inner();
+        bind_client(&mut req.client, &user)?;
         if req.roomname.is_empty() { // create room
             log::error!("roomname is none");
             return Err(Status::invalid_argument("roomname is none"));
         }
 
-        let state_reader = self.state.read().unwrap();
-        let mut map = state_reader.onlinemap.write().unwrap();
-        map.get_mut(&req.roomname).unwrap().remove(&req.client.as_ref().unwrap().username());
+        let state = self.state.read().unwrap();
+        state.publish_presence(state.presence.write().unwrap().leave(&req.roomname, &user.username, common::now_milli_seconds()));
         Ok(Response::new(chat::ServerResponse::default()))
     }
+
+    async fn edit_message(
+        &self,
+        request: Request<chat::EditMessageRequest>
+    ) -> Result<Response<chat::ServerResponse>, Status> {
+        let user = session::authenticate(&request)?;
+        let mut req = request.into_inner();
+        bind_client(&mut req.client, &user)?;
+        if req.roomname.is_empty() || req.id.is_empty() {
+            return Err(Status::invalid_argument("roomname or message id is empty"));
+        }
+        self.check_message_size(&req.bytes)?;
+
+        let state = self.state.read().unwrap();
+        let room = state.rooms.iter().find(|r| r.read().unwrap().name == req.roomname)
+            .ok_or_else(|| Status::invalid_argument("room is none"))?;
+        let mut room_writer = room.write().unwrap();
+        member_only(&room_writer, &user.username)?;
+        let index = room_writer.message_index(&req.id)
+            .ok_or_else(|| Status::not_found(format!("message {} not exist", req.id)))?;
+        let message = &mut room_writer.messages[index];
+        if message.author().as_deref() != Some(user.username.as_str()) {
+            return Err(Status::permission_denied("only the author can edit a message"));
+        }
+        if message.deleted {
+            return Err(Status::failed_precondition("message is deleted"));
+        }
+        log::info!("client [{}] edit message[{}] of room[{}]", user.username, message.seq, req.roomname);
+        message.edit(req.bytes, common::now_milli_seconds());
+        self.update_posted(&state, &room_writer, index);
+
+        Ok(Response::new(chat::ServerResponse {
+            messages: vec![room_writer.messages[index].clone()],
+            ..Default::default()
+        }))
+    }
+
+    async fn delete_message(
+        &self,
+        request: Request<chat::DeleteMessageRequest>
+    ) -> Result<Response<chat::ServerResponse>, Status> {
+        let user = session::authenticate(&request)?;
+        let mut req = request.into_inner();
+        bind_client(&mut req.client, &user)?;
+        if req.roomname.is_empty() || req.id.is_empty() {
+            return Err(Status::invalid_argument("roomname or message id is empty"));
+        }
+
+        let state = self.state.read().unwrap();
+        let room = state.rooms.iter().find(|r| r.read().unwrap().name == req.roomname)
+            .ok_or_else(|| Status::invalid_argument("room is none"))?;
+        let mut room_writer = room.write().unwrap();
+        member_only(&room_writer, &user.username)?;
+        let index = room_writer.message_index(&req.id)
+            .ok_or_else(|| Status::not_found(format!("message {} not exist", req.id)))?;
+        let author = room_writer.messages[index].author().unwrap_or_default();
+        if author != user.username && !room_writer.can_moderate(&user.username, &author) {
+            return Err(Status::permission_denied("only the author or a room moderator can delete a message"));
+        }
+        let message = &mut room_writer.messages[index];
+        if !message.deleted {
+            log::info!("client [{}] delete message[{}] of room[{}]", user.username, message.seq, req.roomname);
+            message.tombstone(&user.username, common::now_milli_seconds());
+            self.update_posted(&state, &room_writer, index);
+        }
+
+        Ok(Response::new(chat::ServerResponse {
+            messages: vec![room_writer.messages[index].clone()],
+            ..Default::default()
+        }))
+    }
+
+    async fn add_reaction(
+        &self,
+        request: Request<chat::ReactionRequest>
+    ) -> Result<Response<chat::ServerResponse>, Status> {
+        self.react(request, chat::Message::react)
+    }
+
+    async fn remove_reaction(
+        &self,
+        request: Request<chat::ReactionRequest>
+    ) -> Result<Response<chat::ServerResponse>, Status> {
+        self.react(request, chat::Message::unreact)
+    }
+
+    async fn get_history(
+        &self,
+        request: Request<chat::GetHistoryRequest>
+    ) -> Result<Response<chat::ServerResponse>, Status> {
+        let user = session::authenticate(&request)?;
+        let mut req = request.into_inner();
+        bind_client(&mut req.client, &user)?;
+        if req.roomname.is_empty() {
+            return Err(Status::invalid_argument("roomname is empty"));
+        }
+
+        let state = self.state.read().unwrap();
+        let room = state.rooms.iter().find(|r| r.read().unwrap().name == req.roomname)
+            .ok_or_else(|| Status::invalid_argument("room is none"))?;
+        let room_reader = room.read().unwrap();
+        if !common::client_in_room(req.client.as_ref().unwrap(), &room_reader) {
+            let msg = format!("client not exist in room {}", req.roomname);
+            log::error!("{}", msg);
+            return Err(Status::invalid_argument(msg));
+        }
+        let page_limit = self.config.history.page_limit;
+        let limit = if req.limit == 0 { page_limit } else { req.limit.min(page_limit) };
+        // 多取一条判断之前是否还有
+        let after_seq = room_reader.join_seq(&user.username);
+        let mut messages = self.storage.query_history(&room_reader, after_seq, req.before_seq, limit as usize + 1)
+            .map_err(|e| {
+                log::error!("query history of room[{}]: {}", req.roomname, e);
+                Status::internal("can not read the history")
+            })?;
+        let more_history = messages.len() > limit as usize;
+        if more_history {
+            messages.remove(0);
+        }
+        Ok(Response::new(chat::ServerResponse {
+            messages,
+            more_history,
+            ..Default::default()
+        }))
+    }
+
+    async fn search(
+        &self,
+        request: Request<chat::SearchRequest>
+    ) -> Result<Response<chat::ServerResponse>, Status> {
+        let user = session::authenticate(&request)?;
+        let mut req = request.into_inner();
+        bind_client(&mut req.client, &user)?;
+        if req.roomname.is_empty() || req.query.trim().is_empty() {
+            return Err(Status::invalid_argument("roomname or query is empty"));
+        }
+        if req.until != 0 && req.until < req.since {
+            return Err(Status::invalid_argument("until is earlier than since"));
+        }
+
+        let state = self.state.read().unwrap();
+        let room = state.rooms.iter().find(|r| r.read().unwrap().name == req.roomname)
+            .ok_or_else(|| Status::invalid_argument("room is none"))?;
+        let room_reader = room.read().unwrap();
+        if !common::client_in_room(req.client.as_ref().unwrap(), &room_reader) {
+            let msg = format!("client not exist in room {}", req.roomname);
+            log::error!("{}", msg);
+            return Err(Status::invalid_argument(msg));
+        }
+        let page_limit = self.config.history.page_limit;
+        let query = SearchQuery {
+            text: &req.query,
+            author: req.author.as_deref().filter(|a| !a.is_empty()),
+            since: req.since,
+            until: req.until,
+            visible_from: room_reader.visible_from(&user.username),
+            limit: if req.limit == 0 { page_limit } else { req.limit.min(page_limit) } as usize,
+        };
+        let messages = state.index.read().unwrap().search(&room_reader, &query);
+        log::info!("client [{}] search {:?} in room[{}]: {} found", user.username, req.query, req.roomname, messages.len());
+        Ok(Response::new(chat::ServerResponse {
+            messages,
+            ..Default::default()
+        }))
+    }
+
+    async fn upload(
+        &self,
+        request: Request<Streaming<chat::UploadRequest>>
+    ) -> Result<Response<chat::ServerResponse>, Status> {
+        let user = session::authenticate(&request)?;
+        let blob = self.receive_upload(&user.username, request.into_inner()).await?;
+        Ok(Response::new(chat::ServerResponse {
+            blob: Some(blob),
+            ..Default::default()
+        }))
+    }
+
+    type downloadStream = ReceiverStream<Result<chat::BlobChunk, Status>>;
+
+    async fn download(
+        &self,
+        request: Request<chat::DownloadRequest>
+    ) -> Result<Response<Self::downloadStream>, Status> {
+        let user = session::authenticate(&request)?;
+        let mut req = request.into_inner();
+        bind_client(&mut req.client, &user)?;
+        if req.roomname.is_empty() || req.id.is_empty() {
+            return Err(Status::invalid_argument("roomname or message id is empty"));
+        }
+
+        let state = self.state.read().unwrap();
+        let room = state.rooms.iter().find(|r| r.read().unwrap().name == req.roomname)
+            .ok_or_else(|| Status::invalid_argument("room is none"))?;
+        let room_reader = room.read().unwrap();
+        if !common::client_in_room(req.client.as_ref().unwrap(), &room_reader) {
+            let msg = format!("client not exist in room {}", req.roomname);
+            log::error!("{}", msg);
+            return Err(Status::invalid_argument(msg));
+        }
+        // 只能下载自己看得到的信息引用的文件
+        let blob = room_reader.visible_messages(&user.username).iter()
+            .find(|m| m.id == req.id)
+            .ok_or_else(|| Status::not_found(format!("message {} not exist", req.id)))?
+            .blob.clone()
+            .ok_or_else(|| Status::not_found(format!("message {} has no file", req.id)))?;
+        drop(room_reader);
+        drop(state);
+        let mut file = self.blobs.path(&blob.hash)
+            .and_then(|path| std::fs::File::open(path).ok())
+            .ok_or_else(|| Status::not_found(format!("blob {} not exist", blob.hash)))?;
+
+        log::info!("client [{}] download blob {}", user.username, blob.hash);
+        let (sender, stream) = mpsc::channel(4);
+        tokio::task::spawn_blocking(move || {
+            use std::io::Read;
+            let mut buf = vec![0; common::BLOB_CHUNK];
+            loop {
+                let chunk = match file.read(&mut buf) {
+                    Ok(0) => break,
+                    Ok(n) => Ok(chat::BlobChunk { data: buf[..n].to_vec() }),
+                    Err(e) => Err(Status::internal(format!("read blob: {}", e))),
+                };
+                let failed = chunk.is_err();
+                if sender.blocking_send(chunk).is_err() || failed {
+                    break;
+                }
+            }
+        });
+        Ok(Response::new(ReceiverStream::new(stream)))
+    }
+
+    async fn kick(
+        &self,
+        request: Request<chat::ModerateRequest>
+    ) -> Result<Response<chat::ServerResponse>, Status> {
+        self.moderate(request, Moderation::Kick)
+    }
+
+    async fn ban(
+        &self,
+        request: Request<chat::ModerateRequest>
+    ) -> Result<Response<chat::ServerResponse>, Status> {
+        self.moderate(request, Moderation::Ban)
+    }
+
+    async fn unban(
+        &self,
+        request: Request<chat::ModerateRequest>
+    ) -> Result<Response<chat::ServerResponse>, Status> {
+        self.moderate(request, Moderation::Unban)
+    }
+
+    async fn promote(
+        &self,
+        request: Request<chat::ModerateRequest>
+    ) -> Result<Response<chat::ServerResponse>, Status> {
+        self.moderate(request, Moderation::Promote)
+    }
+
+    async fn demote(
+        &self,
+        request: Request<chat::ModerateRequest>
+    ) -> Result<Response<chat::ServerResponse>, Status> {
+        self.moderate(request, Moderation::Demote)
+    }
+
+    async fn update_room(
+        &self,
+        request: Request<chat::UpdateRoomRequest>
+    ) -> Result<Response<chat::ServerResponse>, Status> {
+        let user = session::authenticate(&request)?;
+        let mut req = request.into_inner();
+        bind_client(&mut req.client, &user)?;
+        if req.roomname.is_empty() {
+            return Err(Status::invalid_argument("roomname is empty"));
+        }
+        if req.password.is_none() && req.history_visible.is_none() && req.invite_only.is_none() {
+            return Err(Status::invalid_argument("nothing to update"));
+        }
+
+        let state = self.state.read().unwrap();
+        let room = state.rooms.iter().find(|r| r.read().unwrap().name == req.roomname)
+            .ok_or_else(|| Status::not_found(format!("room {} not exist", req.roomname)))?;
+        let mut room_writer = room.write().unwrap();
+        owner_only(&room_writer, &user.username)?;
+        if let Some(password) = req.password {
+            room_writer.password = Some(password).filter(|p| !p.is_empty());
+        }
+        if let Some(history_visible) = req.history_visible {
+            room_writer.history_visible = history_visible;
+        }
+        if let Some(invite_only) = req.invite_only {
+            room_writer.invite_only = invite_only;
+        }
+        log::info!("client [{}] update room[{}]", user.username, req.roomname);
+        self.save_room(&room_writer);
+        state.publish_room_change(&req.roomname, &user.username, Some(&room_writer));
+        Ok(Response::new(chat::ServerResponse {
+            rooms: vec![state.summary(&room_writer)],
+            ..Default::default()
+        }))
+    }
+
+    async fn rename_room(
+        &self,
+        request: Request<chat::RenameRoomRequest>
+    ) -> Result<Response<chat::ServerResponse>, Status> {
+        let user = session::authenticate(&request)?;
+        let mut req = request.into_inner();
+        bind_client(&mut req.client, &user)?;
+        if req.roomname.is_empty() {
+            return Err(Status::invalid_argument("roomname is empty"));
+        }
+        validate_room_name(&req.new_name)?;
+
+        // 改名要移动广播通道等房间名索引的状态，需要写锁
+        let mut state = self.state.write().unwrap();
+        if state.rooms.iter_mut().any(|r| r.get_mut().unwrap().name == req.new_name) {
+            return Err(Status::already_exists(format!("room {} already exists", req.new_name)));
+        }
+        let room = state.rooms.iter_mut().map(|r| r.get_mut().unwrap()).find(|r| r.name == req.roomname)
+            .ok_or_else(|| Status::not_found(format!("room {} not exist", req.roomname)))?;
+        owner_only(room, &user.username)?;
+        room.name = req.new_name.clone();
+        if let Err(e) = self.storage.rename_room(&req.roomname, room) {
+            log::error!("rename room[{}] to [{}]: {}", req.roomname, req.new_name, e);
+            room.name = req.roomname.clone();
+            return Err(Status::internal("can not rename the room"));
+        }
+        let room = room.clone();
+        log::info!("client [{}] rename room[{}] to [{}]", user.username, req.roomname, req.new_name);
+        state.rename_room(&req.roomname, &req.new_name);
+        state.publish_room_change(&req.roomname, &user.username, Some(&room));
+        Ok(Response::new(chat::ServerResponse {
+            rooms: vec![state.summary(&room)],
+            ..Default::default()
+        }))
+    }
+
+    async fn delete_room(
+        &self,
+        request: Request<chat::DeleteRoomRequest>
+    ) -> Result<Response<chat::ServerResponse>, Status> {
+        let user = session::authenticate(&request)?;
+        let mut req = request.into_inner();
+        bind_client(&mut req.client, &user)?;
+        if req.roomname.is_empty() {
+            return Err(Status::invalid_argument("roomname is empty"));
+        }
+
+        let mut state = self.state.write().unwrap();
+        let room = state.rooms.iter_mut().map(|r| r.get_mut().unwrap()).find(|r| r.name == req.roomname)
+            .ok_or_else(|| Status::not_found(format!("room {} not exist", req.roomname)))?;
+        owner_only(room, &user.username)?;
+        if let Err(e) = self.storage.delete_room(&req.roomname) {
+            log::error!("delete room[{}]: {}", req.roomname, e);
+            return Err(Status::internal("can not delete the room"));
+        }
+        log::info!("client [{}] delete room[{}]", user.username, req.roomname);
+        state.publish_room_change(&req.roomname, &user.username, None);
+        state.remove_room(&req.roomname);
+        Ok(Response::new(chat::ServerResponse::default()))
+    }
+
+    async fn transfer_ownership(
+        &self,
+        request: Request<chat::TransferOwnershipRequest>
+    ) -> Result<Response<chat::ServerResponse>, Status> {
+        let user = session::authenticate(&request)?;
+        let mut req = request.into_inner();
+        bind_client(&mut req.client, &user)?;
+        if req.roomname.is_empty() || req.new_owner.is_empty() {
+            return Err(Status::invalid_argument("roomname or new owner is empty"));
+        }
+        if req.new_owner == user.username {
+            return Err(Status::invalid_argument("you already own the room"));
+        }
+
+        let state = self.state.read().unwrap();
+        let room = state.rooms.iter().find(|r| r.read().unwrap().name == req.roomname)
+            .ok_or_else(|| Status::not_found(format!("room {} not exist", req.roomname)))?;
+        let mut room_writer = room.write().unwrap();
+        owner_only(&room_writer, &user.username)?;
+        let new_owner = room_writer.clients.iter().find(|c| c.username() == req.new_owner).cloned()
+            .ok_or_else(|| Status::failed_precondition(format!("{} is not a member", req.new_owner)))?;
+        room_writer.manner = Some(new_owner);
+        room_writer.roles.remove(&req.new_owner);
+        room_writer.roles.insert(user.username.clone(), chat::Role::Moderator as i32);
+        log::info!("client [{}] transfer room[{}] to [{}]", user.username, req.roomname, req.new_owner);
+        self.save_room(&room_writer);
+        state.publish_room_change(&req.roomname, &user.username, Some(&room_writer));
+        Ok(Response::new(chat::ServerResponse {
+            rooms: vec![state.summary(&room_writer)],
+            ..Default::default()
+        }))
+    }
+
+    async fn create_invite(
+        &self,
+        request: Request<chat::CreateInviteRequest>
+    ) -> Result<Response<chat::ServerResponse>, Status> {
+        let user = session::authenticate(&request)?;
+        let mut req = request.into_inner();
+        bind_client(&mut req.client, &user)?;
+        if req.roomname.is_empty() {
+            return Err(Status::invalid_argument("roomname is empty"));
+        }
+        let uses = req.uses.max(1);
+        let ttl_secs = if req.ttl_secs == 0 { DEFAULT_INVITE_TTL_SECS } else { req.ttl_secs };
+        if uses > MAX_INVITE_USES || ttl_secs > MAX_INVITE_TTL_SECS {
+            return Err(Status::invalid_argument(format!(
+                "an invite can be used at most {} times and last at most {} days", MAX_INVITE_USES, MAX_INVITE_TTL_SECS / 86400)));
+        }
+
+        let state = self.state.read().unwrap();
+        let room = state.rooms.iter().find(|r| r.read().unwrap().name == req.roomname)
+            .ok_or_else(|| Status::invalid_argument("room is none"))?;
+        let mut room_writer = room.write().unwrap();
+        invite_manager(&room_writer, &user.username)?;
+        let now = common::now_milli_seconds();
+        room_writer.prune_invites(now);
+        let invite = chat::Invite {
+            code: common::random_hex(8),
+            by: user.username.clone(),
+            uses_left: uses,
+            expires: now + ttl_secs * 1000,
+            created: now,
+        };
+        room_writer.invites.push(invite.clone());
+        log::info!("client [{}] create an invite of room[{}] for {} uses", user.username, req.roomname, uses);
+        self.save_room(&room_writer);
+        Ok(Response::new(chat::ServerResponse {
+            invites: vec![invite],
+            ..Default::default()
+        }))
+    }
+
+    async fn list_invites(
+        &self,
+        request: Request<chat::ListInvitesRequest>
+    ) -> Result<Response<chat::ServerResponse>, Status> {
+        let user = session::authenticate(&request)?;
+        let mut req = request.into_inner();
+        bind_client(&mut req.client, &user)?;
+
+        let state = self.state.read().unwrap();
+        let room = state.rooms.iter().find(|r| r.read().unwrap().name == req.roomname)
+            .ok_or_else(|| Status::invalid_argument("room is none"))?;
+        let room_reader = room.read().unwrap();
+        invite_manager(&room_reader, &user.username)?;
+        let now = common::now_milli_seconds();
+        Ok(Response::new(chat::ServerResponse {
+            invites: room_reader.invites.iter().filter(|i| i.expires > now).cloned().collect(),
+            ..Default::default()
+        }))
+    }
+
+    async fn revoke_invite(
+        &self,
+        request: Request<chat::RevokeInviteRequest>
+    ) -> Result<Response<chat::ServerResponse>, Status> {
+        let user = session::authenticate(&request)?;
+        let mut req = request.into_inner();
+        bind_client(&mut req.client, &user)?;
+
+        let state = self.state.read().unwrap();
+        let room = state.rooms.iter().find(|r| r.read().unwrap().name == req.roomname)
+            .ok_or_else(|| Status::invalid_argument("room is none"))?;
+        let mut room_writer = room.write().unwrap();
+        invite_manager(&room_writer, &user.username)?;
+        room_writer.prune_invites(common::now_milli_seconds());
+        let count = room_writer.invites.len();
+        room_writer.invites.retain(|i| i.code != req.code);
+        if room_writer.invites.len() == count {
+            return Err(Status::not_found(format!("invite {} not exist", req.code)));
+        }
+        log::info!("client [{}] revoke an invite of room[{}]", user.username, req.roomname);
+        self.save_room(&room_writer);
+        Ok(Response::new(chat::ServerResponse {
+            invites: room_writer.invites.clone(),
+            ..Default::default()
+        }))
+    }
+
+    async fn get_thread(
+        &self,
+        request: Request<chat::GetThreadRequest>
+    ) -> Result<Response<chat::ServerResponse>, Status> {
+        let user = session::authenticate(&request)?;
+        let mut req = request.into_inner();
+        bind_client(&mut req.client, &user)?;
+        if req.roomname.is_empty() || req.id.is_empty() {
+            return Err(Status::invalid_argument("roomname or message id is empty"));
+        }
+
+        let state = self.state.read().unwrap();
+        let room = state.rooms.iter().find(|r| r.read().unwrap().name == req.roomname)
+            .ok_or_else(|| Status::invalid_argument("room is none"))?;
+        let room_reader = room.read().unwrap();
+        if !common::client_in_room(req.client.as_ref().unwrap(), &room_reader) {
+            let msg = format!("client not exist in room {}", req.roomname);
+            log::error!("{}", msg);
+            return Err(Status::invalid_argument(msg));
+        }
+        let messages = room_reader.thread(&user.username, &req.id)
+            .ok_or_else(|| Status::not_found(format!("message {} not exist", req.id)))?;
+        Ok(Response::new(chat::ServerResponse {
+            messages,
+            ..Default::default()
+        }))
+    }
+
+    async fn send_direct(
+        &self,
+        request: Request<chat::SendDirectRequest>
+    ) -> Result<Response<chat::ServerResponse>, Status> {
+        let user = session::authenticate(&request)?;
+        let mut req = request.into_inner();
+        bind_client(&mut req.client, &user)?;
+        if req.to.is_empty() {
+            return Err(Status::invalid_argument("recipient is empty"));
+        }
+        if req.to == user.username {
+            return Err(Status::invalid_argument("can not send direct message to yourself"));
+        }
+        let me = req.client.unwrap();
+        let roomname = self.direct_room(&me, &req.to)?;
+
+        let state = self.state.read().unwrap();
+        let room = state.rooms.iter().find(|r| r.read().unwrap().name == roomname)
+            .ok_or_else(|| Status::not_found(format!("room {} not exist", roomname)))?;
+        let mut room_writer = room.write().unwrap();
+        if let Some(mut message) = req.message {
+            message.client = Some(me);
+            self.post_message(&state, &mut room_writer, message)?;
+        }
+        let response = chat::ServerResponse {
+            conversations: vec![room_writer.conversation(&user.username)],
+            ..Default::default()
+        };
+        drop(room_writer);
+
+        state.publish_presence(state.presence.write().unwrap().active(&roomname, &user.username, common::now_milli_seconds()));
+        Ok(Response::new(response))
+    }
+
+    async fn list_conversations(
+        &self,
+        request: Request<chat::ListConversationsRequest>
+    ) -> Result<Response<chat::ServerResponse>, Status> {
+        let user = session::authenticate(&request)?;
+        let mut req = request.into_inner();
+        bind_client(&mut req.client, &user)?;
+
+        let state = self.state.read().unwrap();
+        let mut conversations: Vec<chat::Conversation> = state.rooms.iter()
+            .map(|r| r.read().unwrap())
+            .filter(|room_reader| room_reader.direct && common::client_in_room(req.client.as_ref().unwrap(), room_reader))
+            .map(|room_reader| room_reader.conversation(&user.username))
+            .collect();
+        // 最近有信息的会话在前
+        conversations.sort_by_key(|c| std::cmp::Reverse(c.last_message.as_ref().map(|m| m.time).unwrap_or(0)));
+        Ok(Response::new(chat::ServerResponse {
+            conversations,
+            ..Default::default()
+        }))
+    }
+
+    async fn logout(
+        &self,
+        request: Request<chat::LogoutRequest>
+    ) -> Result<Response<chat::ServerResponse>, Status> {
+        let user = session::authenticate(&request)?;
+        self.sessions.revoke(&user.token);
+        log::info!("client [{}] logout", user.username);
+        Ok(Response::new(chat::ServerResponse::default()))
+    }
+
+    type subscribeStream = ReceiverStream<Result<chat::RoomEvent, Status>>;
+
+    async fn subscribe(
+        &self,
+        request: Request<chat::JoinRequest>
+    ) -> Result<Response<Self::subscribeStream>, Status> {
+        let user = session::authenticate(&request)?;
+        let mut req = request.into_inner();
+        bind_client(&mut req.client, &user)?;
+        if req.roomname.is_empty() {
+            log::error!("roomname is none");
+            return Err(Status::invalid_argument("roomname is none"));
+        }
+        let username = user.username;
+        let mut roomname = req.roomname.clone();
+        if *self.shutdown.borrow() {
+            return Err(Status::unavailable("server is shutting down"));
+        }
+
+        let state = self.state.read().unwrap();
+        let room = state.rooms.iter().find(|x| {
+            let room_reader = x.read().unwrap();
+            room_reader.name == roomname
+        });
+        if room.is_none() {
+            return Err(Status::invalid_argument("subscribe a non exist room"));
+        }
+
+        // 持有房间读锁时订阅广播并取出漏掉的信息，send无法在两者之间插入新信息
+        let room_reader = room.unwrap().read().unwrap();
+        if !common::client_in_room(req.client.as_ref().unwrap(), &room_reader) {
+            let msg = format!("client not exist in room {}", roomname);
+            log::error!("{}", msg);
+            return Err(Status::invalid_argument(msg));
+        }
+        // 和heartbeat一样至多补发一页，只补最新的，更早的由客户端用get_history取
+        let missed = room_reader.visible_after(&username, req.after_seq.unwrap_or(0));
+        let missed = missed[missed.len().saturating_sub(self.config.history.page_limit as usize)..].to_vec();
+        let mut receiver = state.broadcasts.get(&roomname).unwrap().subscribe();
+        drop(room_reader);
+
+        // 订阅期间由推送流本身表示在线，不再依赖heartbeat
+        state.publish_presence(state.presence.write().unwrap().subscribe(&roomname, &username, common::now_milli_seconds()));
+        drop(state);
+        let state = Arc::clone(&self.state);
+
+        log::info!("client [{}] subscribe room[{}]", username, roomname);
+        let mut stopping = self.shutdown.subscribe();
+        let (sender, stream) = mpsc::channel(BROADCAST_CAPACITY);
+        tokio::spawn(async move {
+            for message in missed {
+                let event = chat::RoomEvent { event: Some(chat::room_event::Event::Message(message)) };
+                if sender.send(Ok(event)).await.is_err() {
+                    break;
+                }
+            }
+            loop {
+                tokio::select! {
+                    // client disconnected
+                    _ = sender.closed() => break,
+                    _ = async { let _ = stopping.wait_for(|stop| *stop).await; } => {
+                        let _ = sender.send(Err(Status::unavailable("server is shutting down"))).await;
+                        break;
+                    },
+                    received = receiver.recv() => match received {
+                        Ok(event) => {
+                            // 被踢出或封禁的用户不再收到这个房间的事件
+                            let end = match &event.event {
+                                Some(chat::room_event::Event::Member(change)) if change.username == username
+                                    && matches!(change.role(), chat::Role::NotMember | chat::Role::Banned) =>
+                                    Some(Status::permission_denied("removed from the room")),
+                                Some(chat::room_event::Event::Room(change)) => match &change.room {
+                                    Some(room) => {
+                                        roomname = room.name.clone();
+                                        None
+                                    },
+                                    None => Some(Status::not_found("room deleted")),
+                                },
+                                _ => None,
+                            };
+                            if sender.send(Ok(event)).await.is_err() {
+                                break;
+                            }
+                            if let Some(status) = end {
+                                let _ = sender.send(Err(status)).await;
+                                break;
+                            }
+                        },
+                        // 跟不上的订阅者丢了事件，结束推送流，由客户端带着最后的seq重新订阅补齐
+                        Err(broadcast::error::RecvError::Lagged(n)) => {
+                            log::warn!("client [{}] lagged {} messages in room[{}]", username, n, roomname);
+                            let _ = sender.send(Err(Status::data_loss(format!("missed {} events, subscribe again", n)))).await;
+                            break;
+                        },
+                        Err(broadcast::error::RecvError::Closed) => break,
+                    },
+                }
+            }
+            let state = state.read().unwrap();
+            state.publish_presence(state.presence.write().unwrap().unsubscribe(&roomname, &username, common::now_milli_seconds()));
+            log::info!("client [{}] unsubscribe room[{}]", username, roomname);
+        });
+
+        Ok(Response::new(ReceiverStream::new(stream)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashSet;
+    use tokio_stream::StreamExt;
+    use crate::server::roomlog;
+    use crate::server::storage::FileStorage;
+    use crate::server::storage::tests::TempDir;
+    use crate::server::config::LimitsConfig;
+    use crate::server::sqlite::SqliteStorage;
+
+    const USER_PASSWORD: &str = "user-secret-pw";
+    const ROOM_PASSWORD: &str = "room-secret-pw";
+
+    fn test_config(datapath: &str) -> Config {
+        Config {
+            datapath: datapath.to_string(),
+            ..Default::default()
+        }
+    }
+
+    fn test_server(datapath: &str) -> MyChatServer<FileStorage> {
+        server_with(test_config(datapath))
+    }
+
+    // 各功能的测试服务器只在配置上不同
+    fn server_with(config: Config) -> MyChatServer<FileStorage> {
+        let storage = FileStorage::open(&config.datapath).unwrap();
+        let server = MyChatServer::new(config, storage);
+        server.init().unwrap();
+        server
+    }
+
+    fn sqlite_server(datapath: &str) -> MyChatServer<SqliteStorage> {
+        let server = MyChatServer::new(test_config(datapath), SqliteStorage::open(datapath).unwrap());
+        server.init().unwrap();
+        server
+    }
+
+    // an old client that still puts its password into every request
+    fn client(name: &str) -> chat::Client {
+        chat::Client {
+            user: Some(chat::User {
+                name: name.to_string(),
+                password: USER_PASSWORD.to_string(),
+                gender: Some(1),
+            }),
+            device: Some(chat::Device::default()),
+        }
+    }
+
+    fn authed<T>(name: &str, message: T) -> Request<T> {
+        let mut request = Request::new(message);
+        request.extensions_mut().insert(session::SessionUser {
+            username: name.to_string(),
+            token: "test-token".to_string(),
+            device: None,
+        });
+        request
+    }
+
+    // 模拟崩溃：跳过Drop中最后的写盘
+    fn crash<S: Storage>(server: MyChatServer<S>) {
+        std::mem::forget(server);
+    }
+
+    // 经过真实TCP连接和SessionInterceptor的服务器
+    async fn serve(server: MyChatServer<FileStorage>) -> chat::chat_client::ChatClient<tonic::transport::Channel> {
+        let interceptor = session::SessionInterceptor::new(server.sessions.clone());
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap().to_string();
+        tokio::spawn(tonic::transport::Server::builder()
+            .add_service(chat::chat_server::ChatServer::with_interceptor(server, interceptor))
+            .serve_with_incoming(tokio_stream::wrappers::TcpListenerStream::new(listener)));
+        crate::client::clib::connect(&addr, None).await.unwrap()
+    }
+
+    // 注册用户，返回会话令牌
+    async fn signup(channel: &mut chat::chat_client::ChatClient<tonic::transport::Channel>, name: &str) -> String {
+        channel.signup(chat::UserSignupRequest {
+            client: Some(client(name)),
+            password: USER_PASSWORD.to_string(),
+        }).await.unwrap().into_inner().token
+    }
+
+    // 把测试中直接注入的会话换成请求头里的令牌，交给拦截器验证
+    fn over<T>(token: &str, request: Request<T>) -> Request<T> {
+        let mut request = Request::new(request.into_inner());
+        let value = format!("{}{}", common::BEARER_PREFIX, token);
+        request.metadata_mut().insert(common::AUTH_METADATA, value.parse().unwrap());
+        request
+    }
+
+    fn createroom_req(name: &str, roomname: &str, password: Option<&str>, history_visible: bool)
+        -> Request<chat::CreateRoomRequest> {
+        authed(name, chat::CreateRoomRequest {
+            client: Some(client(name)),
+            roomname: roomname.to_string(),
+            password: password.map(|p| p.to_string()),
+            history_visible,
+        })
+    }
+
+    fn join_req(name: &str, roomname: &str, room_password: Option<&str>) -> Request<chat::JoinRequest> {
+        authed(name, chat::JoinRequest {
+            client: Some(client(name)),
+            roomname: roomname.to_string(),
+            room_password: room_password.map(|p| p.to_string()),
+            after_seq: None,
+            invite_code: None,
+        })
+    }
+
+    fn heartbeat_req(name: &str, roomname: &str, after_seq: u64) -> Request<chat::HeartBeatRequest> {
+        authed(name, chat::HeartBeatRequest {
+            client: Some(client(name)),
+            roomname: roomname.to_string(),
+            room_password: None,
+            lasttime: 0,
+            after_seq,
+        })
+    }
+
+    fn send_req(name: &str, roomname: &str, text: &str) -> Request<chat::SendRequest> {
+        authed(name, chat::SendRequest {
+            client: Some(client(name)),
+            message: Some(chat::Message {
+                msg_type: chat::MessageType::Text as i32,
+                bytes: text.as_bytes().to_vec(),
+                client: Some(client(name)),
+                ..Default::default()
+            }),
+            roomname: roomname.to_string(),
+            room_password: None,
+        })
+    }
+
+    fn texts(messages: &[chat::Message]) -> Vec<String> {
+        messages.iter().map(|m| String::from_utf8(m.bytes.clone()).unwrap()).collect()
+    }
+
+    // 跳过在线状态事件，返回订阅流中的下一条信息
+    async fn next_message(stream: &mut ReceiverStream<Result<chat::RoomEvent, Status>>) -> chat::Message {
+        loop {
+            if let Some(chat::room_event::Event::Message(message)) = stream.next().await.unwrap().unwrap().event {
+                return message;
+            }
+        }
+    }
+
+    async fn next_presence(stream: &mut ReceiverStream<Result<chat::RoomEvent, Status>>) -> (String, chat::PresenceState) {
+        loop {
+            if let Some(chat::room_event::Event::Presence(change)) = stream.next().await.unwrap().unwrap().event {
+                return (change.username.clone(), change.state());
+            }
+        }
+    }
+
+    async fn next_updated(stream: &mut ReceiverStream<Result<chat::RoomEvent, Status>>) -> chat::Message {
+        loop {
+            if let Some(chat::room_event::Event::Updated(message)) = stream.next().await.unwrap().unwrap().event {
+                return message;
+            }
+        }
+    }
+
+    fn edit_req(name: &str, roomname: &str, id: &str, text: &str) -> Request<chat::EditMessageRequest> {
+        authed(name, chat::EditMessageRequest {
+            client: Some(client(name)),
+            roomname: roomname.to_string(),
+            id: id.to_string(),
+            bytes: text.as_bytes().to_vec(),
+        })
+    }
+
+    fn delete_req(name: &str, roomname: &str, id: &str) -> Request<chat::DeleteMessageRequest> {
+        authed(name, chat::DeleteMessageRequest {
+            client: Some(client(name)),
+            roomname: roomname.to_string(),
+            id: id.to_string(),
+        })
+    }
+
+    fn thread_req(name: &str, roomname: &str, id: &str) -> Request<chat::GetThreadRequest> {
+        authed(name, chat::GetThreadRequest {
+            client: Some(client(name)),
+            roomname: roomname.to_string(),
+            id: id.to_string(),
+        })
+    }
+
+    fn reaction_req(name: &str, roomname: &str, id: &str, emoji: &str) -> Request<chat::ReactionRequest> {
+        authed(name, chat::ReactionRequest {
+            client: Some(client(name)),
+            roomname: roomname.to_string(),
+            id: id.to_string(),
+            emoji: emoji.to_string(),
+        })
+    }
+
+    fn assert_no_credential<M: prost::Message>(message: &M) {
+        let buf = message.encode_to_vec();
+        for secret in [USER_PASSWORD, ROOM_PASSWORD, "$argon2"] {
+            assert!(!buf.windows(secret.len()).any(|w| w == secret.as_bytes()),
+                "response leaks credential {}", secret);
+        }
+    }
+
+    #[tokio::test]
+    async fn no_credential_in_responses() {
+        let dir = TempDir::new();
+        let datapath = dir.path();
+        let server = test_server(datapath);
+
+        let response = server.signup(Request::new(chat::UserSignupRequest {
+            client: Some(client("alice")),
+            password: USER_PASSWORD.to_string(),
+        })).await.unwrap().into_inner();
+        assert_no_credential(&response);
+
+        let response = server.createroom(createroom_req("alice", "r1", Some(ROOM_PASSWORD), true))
+            .await.unwrap().into_inner();
+        assert_no_credential(&response);
+
+        let response = server.join(join_req("alice", "r1", Some(ROOM_PASSWORD))).await.unwrap().into_inner();
+        assert_no_credential(&response);
+
+        let response = server.send(send_req("alice", "r1", "hello")).await.unwrap().into_inner();
+        assert_no_credential(&response);
+
+        let response = server.join(join_req("alice", "r1", Some(ROOM_PASSWORD))).await.unwrap().into_inner();
+        assert_eq!(response.messages.len(), 1);
+        assert_no_credential(&response);
+
+        let response = server.heartbeat(heartbeat_req("alice", "r1", 0)).await.unwrap().into_inner();
+        assert_eq!(response.messages.len(), 1);
+        assert_no_credential(&response);
+
+        let mut subscribe = join_req("alice", "r1", Some(ROOM_PASSWORD));
+        subscribe.get_mut().after_seq = Some(0);
+        let mut stream = server.subscribe(subscribe).await.unwrap().into_inner();
+        let message = stream.next().await.unwrap().unwrap();
+        assert_no_credential(&message);
+
+        let response = server.getrooms(authed("alice", chat::GetRoomsRequest {
+            client: Some(client("alice")),
+        })).await.unwrap().into_inner();
+        assert_eq!(response.rooms.len(), 1);
+        assert!(response.rooms[0].has_password);
+        assert_no_credential(&response);
+
+        let response = server.getusers(authed("alice", chat::GetUsersRequest {
+            client: Some(client("alice")),
+        })).await.unwrap().into_inner();
+        assert_eq!(response.users.len(), 1);
+        assert_no_credential(&response);
+
+        // 后来加入的RPC同样不能带出凭据
+        let sent = server.send(send_req("alice", "r1", "second")).await.unwrap().into_inner().messages;
+        let mut reply = send_req("alice", "r1", "a reply");
+        reply.get_mut().message.as_mut().unwrap().reply_to = sent[0].id.clone();
+        server.send(reply).await.unwrap();
+        let responses = vec![
+            server.edit_message(edit_req("alice", "r1", &sent[0].id, "edited")).await.unwrap().into_inner(),
+            server.add_reaction(reaction_req("alice", "r1", &sent[0].id, "👍")).await.unwrap().into_inner(),
+            server.get_thread(thread_req("alice", "r1", &sent[0].id)).await.unwrap().into_inner(),
+            server.get_history(history_req("alice", 0, 10)).await.unwrap().into_inner(),
+            server.search(search_req("alice", "edited", None, 0)).await.unwrap().into_inner(),
+            server.update_room(update_req("alice", "r1", None, Some(false))).await.unwrap().into_inner(),
+            server.create_invite(create_invite_req("alice", 1, 0)).await.unwrap().into_inner(),
+            server.list_invites(list_invites_req("alice")).await.unwrap().into_inner(),
+        ];
+        for response in &responses {
+            assert_no_credential(response);
+        }
+        assert!(!responses[2].messages.is_empty() && !responses[3].messages.is_empty() && !responses[4].messages.is_empty());
+        assert_no_credential(&stream.next().await.unwrap().unwrap());
+
+        server.signup(Request::new(chat::UserSignupRequest {
+            client: Some(client("bob")),
+            password: USER_PASSWORD.to_string(),
+        })).await.unwrap();
+        let response = server.send_direct(direct_req("alice", "bob", Some("psst"))).await.unwrap().into_inner();
+        assert_no_credential(&response);
+        let response = server.list_conversations(conversations_req("alice")).await.unwrap().into_inner();
+        assert_eq!(response.conversations.len(), 1);
+        assert_no_credential(&response);
+
+        let response = server.exitroom(authed("alice", chat::ExitRoomRequest {
+            client: Some(client("alice")),
+            roomname: "r1".to_string(),
+        })).await.unwrap().into_inner();
+        assert_no_credential(&response);
+
+        let response = server.logout(authed("alice", chat::LogoutRequest {
+            client: Some(client("alice")),
+        })).await.unwrap().into_inner();
+        assert_no_credential(&response);
+    }
+
+    #[tokio::test]
+    async fn signup_rejects_incomplete_request() {
+        let dir = TempDir::new();
+        let server = test_server(dir.path());
+        let err = server.signup(Request::new(chat::UserSignupRequest::default())).await.unwrap_err();
+        assert_eq!(err.code(), tonic::Code::InvalidArgument);
+        let err = server.signup(Request::new(chat::UserSignupRequest {
+            client: Some(chat::Client::default()),
+            password: USER_PASSWORD.to_string(),
+        })).await.unwrap_err();
+        assert_eq!(err.code(), tonic::Code::InvalidArgument);
+    }
+
+    // 除signup外的每个rpc都经过拦截器：没有令牌或令牌伪造都被拒绝，身份只来自令牌
+    #[tokio::test]
+    async fn interceptor_guards_every_rpc() {
+        let dir = TempDir::new();
+        let mut channel = serve(test_server(dir.path())).await;
+        macro_rules! unauthenticated {
+            ($($rpc:ident($req:expr),)*) => {$(
+                let err = channel.$rpc(Request::new($req)).await.unwrap_err();
+                assert_eq!(err.code(), tonic::Code::Unauthenticated, "{} without token", stringify!($rpc));
+                let err = channel.$rpc(over("forged", Request::new($req))).await.unwrap_err();
+                assert_eq!(err.code(), tonic::Code::Unauthenticated, "{} with forged token", stringify!($rpc));
+            )*};
+        }
+        unauthenticated! {
+            getrooms(chat::GetRoomsRequest::default()),
+            getusers(chat::GetUsersRequest::default()),
+            createroom(chat::CreateRoomRequest::default()),
+            heartbeat(chat::HeartBeatRequest::default()),
+            join(chat::JoinRequest::default()),
+            get_history(chat::GetHistoryRequest::default()),
+            exitroom(chat::ExitRoomRequest::default()),
+            send(chat::SendRequest::default()),
+            logout(chat::LogoutRequest::default()),
+            edit_message(chat::EditMessageRequest::default()),
+            delete_message(chat::DeleteMessageRequest::default()),
+            add_reaction(chat::ReactionRequest::default()),
+            remove_reaction(chat::ReactionRequest::default()),
+            kick(chat::ModerateRequest::default()),
+            ban(chat::ModerateRequest::default()),
+            unban(chat::ModerateRequest::default()),
+            promote(chat::ModerateRequest::default()),
+            demote(chat::ModerateRequest::default()),
+            update_room(chat::UpdateRoomRequest::default()),
+            rename_room(chat::RenameRoomRequest::default()),
+            delete_room(chat::DeleteRoomRequest::default()),
+            transfer_ownership(chat::TransferOwnershipRequest::default()),
+            create_invite(chat::CreateInviteRequest::default()),
+            list_invites(chat::ListInvitesRequest::default()),
+            revoke_invite(chat::RevokeInviteRequest::default()),
+            get_thread(chat::GetThreadRequest::default()),
+            send_direct(chat::SendDirectRequest::default()),
+            list_conversations(chat::ListConversationsRequest::default()),
+            search(chat::SearchRequest::default()),
+            upload(tokio_stream::iter([chat::UploadRequest::default()])),
+            download(chat::DownloadRequest::default()),
+            subscribe(chat::JoinRequest::default()),
+        }
+
+        // the client field claims bob, the token says alice
+        let alice = signup(&mut channel, "alice").await;
+        let bob = signup(&mut channel, "bob").await;
+        channel.createroom(over(&alice, createroom_req("bob", "r1", None, true))).await.unwrap();
+        let sent = channel.send(over(&alice, send_req("bob", "r1", "hi"))).await.unwrap().into_inner().messages;
+        assert_eq!(sent[0].author().as_deref(), Some("alice"));
+        let err = channel.send(over(&bob, send_req("alice", "r1", "hi"))).await.unwrap_err();
+        assert_eq!(err.code(), tonic::Code::InvalidArgument);
+    }
+
+    #[tokio::test]
+    async fn names_stay_inside_datapath() {
+        let dir = TempDir::new();
+        let server = test_server(dir.path());
+        for name in ["", "../eve", "a/b", "a\\b", ".hidden", ".tmp-room_x", "a..b"] {
+            let err = server.signup(Request::new(chat::UserSignupRequest {
+                client: Some(client(name)),
+                password: USER_PASSWORD.to_string(),
+            })).await.unwrap_err();
+            assert_eq!(err.code(), tonic::Code::InvalidArgument, "{}", name);
+            let err = server.createroom(createroom_req("alice", name, None, true)).await.unwrap_err();
+            assert_eq!(err.code(), tonic::Code::InvalidArgument, "{}", name);
+        }
+        // ':' separates the two users of a direct room
+        let err = server.signup(Request::new(chat::UserSignupRequest {
+            client: Some(client("a:b")),
+            password: USER_PASSWORD.to_string(),
+        })).await.unwrap_err();
+        assert_eq!(err.code(), tonic::Code::InvalidArgument);
+        // a name that merely ends like the old temporary files survives a restart
+        server.createroom(createroom_req("alice", "x.tmp", None, true)).await.unwrap();
+        drop(server);
+        let server = test_server(dir.path());
+        let response = server.getrooms(authed("alice", chat::GetRoomsRequest {
+            client: Some(client("alice")),
+        })).await.unwrap().into_inner();
+        assert_eq!(room_names(&response), ["x.tmp"]);
+    }
+
+    #[tokio::test]
+    async fn room_password_required_once() {
+        let dir = TempDir::new();
+        let datapath = dir.path();
+        let server = test_server(datapath);
+        server.createroom(createroom_req("alice", "private", Some(ROOM_PASSWORD), true)).await.unwrap();
+
+        let response = server.join(join_req("bob", "private", None)).await.unwrap().into_inner();
+        assert_eq!(response.code, chat::ResponseCode::RoomPasswordWrong as i32);
+        let response = server.join(join_req("bob", "private", Some("guess"))).await.unwrap().into_inner();
+        assert_eq!(response.code, chat::ResponseCode::RoomPasswordWrong as i32);
+        assert!(server.heartbeat(heartbeat_req("bob", "private", 0)).await.is_err());
+        assert!(server.send(send_req("bob", "private", "let me in")).await.is_err());
+
+        let response = server.join(join_req("bob", "private", Some(ROOM_PASSWORD))).await.unwrap().into_inner();
+        assert_eq!(response.code, chat::ResponseCode::Ok as i32);
+        // admitted members do not need the password anymore
+        let response = server.join(join_req("bob", "private", None)).await.unwrap().into_inner();
+        assert_eq!(response.code, chat::ResponseCode::Ok as i32);
+        assert!(server.send(send_req("bob", "private", "hi")).await.is_ok());
+        assert!(server.heartbeat(heartbeat_req("bob", "private", 0)).await.is_ok());
+    }
+
+    #[tokio::test]
+    async fn hidden_history_for_newcomers() {
+        let dir = TempDir::new();
+        let datapath = dir.path();
+        let server = test_server(datapath);
+        server.createroom(createroom_req("alice", "quiet", None, false)).await.unwrap();
+        server.createroom(createroom_req("alice", "open", None, true)).await.unwrap();
+        for roomname in ["quiet", "open"] {
+            server.send(send_req("alice", roomname, "before")).await.unwrap();
+        }
+
+        let response = server.join(join_req("bob", "quiet", None)).await.unwrap().into_inner();
+        assert!(response.messages.is_empty());
+        let response = server.join(join_req("bob", "open", None)).await.unwrap().into_inner();
+        assert_eq!(texts(&response.messages), ["before"]);
+
+        server.send(send_req("alice", "quiet", "after")).await.unwrap();
+        let response = server.heartbeat(heartbeat_req("bob", "quiet", 0)).await.unwrap().into_inner();
+        assert_eq!(texts(&response.messages), ["after"]);
+        let response = server.heartbeat(heartbeat_req("bob", "quiet", 2)).await.unwrap().into_inner();
+        assert!(response.messages.is_empty());
+        // rejoining does not move the join point
+        let response = server.join(join_req("bob", "quiet", None)).await.unwrap().into_inner();
+        assert_eq!(texts(&response.messages), ["after"]);
+        // the owner still sees everything
+        let response = server.join(join_req("alice", "quiet", None)).await.unwrap().into_inner();
+        assert_eq!(texts(&response.messages), ["before", "after"]);
+    }
+
+    #[test]
+    fn join_point_is_a_seq() {
+        let room = chat::Room {
+            messages: [1, 2, 5, 6].iter().map(|&seq| chat::Message { seq, ..Default::default() }).collect(),
+            join_points: HashMap::from([("bob".to_string(), 2), ("carol".to_string(), 6)]),
+            ..Default::default()
+        };
+        assert_eq!(room.visible_from("bob"), 2);
+        assert_eq!(room.visible_from("carol"), 4);
+        assert_eq!(room.visible_from("alice"), 0);
+    }
+
+    #[tokio::test]
+    async fn replay_message_log_after_crash() {
+        let dir = TempDir::new();
+        let datapath = dir.path();
+        let server = test_server(datapath);
+        server.signup(Request::new(chat::UserSignupRequest {
+            client: Some(client("bedroom")),
+            password: USER_PASSWORD.to_string(),
+        })).await.unwrap();
+        server.createroom(createroom_req("bedroom", "r1", None, true)).await.unwrap();
+        for text in ["a", "b", "c"] {
+            server.send(send_req("bedroom", "r1", text)).await.unwrap();
+        }
+        // sends only append to the log, the snapshot is untouched
+        let snapshot = chat::Room::from_file(&roomlog::room_path(datapath, "r1")).unwrap();
+        assert!(snapshot.messages.is_empty());
+        // crash without the final serialize in drop
+        crash(server);
+
+        let server = test_server(datapath);
+        let response = server.join(join_req("bedroom", "r1", None)).await.unwrap().into_inner();
+        assert_eq!(texts(&response.messages), ["a", "b", "c"]);
+        let response = server.getrooms(authed("bedroom", chat::GetRoomsRequest {
+            client: Some(client("bedroom")),
+        })).await.unwrap().into_inner();
+        assert_eq!(response.rooms.len(), 1);
+        assert!(!std::path::Path::new(&roomlog::log_path(datapath, "r1")).exists());
+    }
+
+    #[tokio::test]
+    async fn sqlite_survives_crash() {
+        let dir = TempDir::new();
+        let datapath = dir.path();
+        let server = sqlite_server(datapath);
+        server.createroom(createroom_req("alice", "r1", None, false)).await.unwrap();
+        server.send(send_req("alice", "r1", "a")).await.unwrap();
+        server.join(join_req("bob", "r1", None)).await.unwrap();
+        server.send(send_req("bob", "r1", "b")).await.unwrap();
+        crash(server);
+
+        let server = sqlite_server(datapath);
+        let response = server.join(join_req("alice", "r1", None)).await.unwrap().into_inner();
+        assert_eq!(texts(&response.messages), ["a", "b"]);
+        // membership and join point are kept as well
+        let response = server.join(join_req("bob", "r1", None)).await.unwrap().into_inner();
+        assert_eq!(texts(&response.messages), ["b"]);
+    }
+
+    #[tokio::test]
+    async fn lagging_subscriber_resubscribes_from_last_seq() {
+        let dir = TempDir::new();
+        let server = test_server(dir.path());
+        server.createroom(createroom_req("alice", "r1", None, true)).await.unwrap();
+        server.join(join_req("bob", "r1", None)).await.unwrap();
+        let mut subscribe = join_req("bob", "r1", None);
+        subscribe.get_mut().after_seq = Some(0);
+        let mut stream = server.subscribe(subscribe).await.unwrap().into_inner();
+        // bob不读推送流，转发队列和广播都被塞满
+        for _ in 0..3 * BROADCAST_CAPACITY {
+            server.state.read().unwrap().publish("r1", chat::room_event::Event::Presence(chat::PresenceChange {
+                username: "carol".to_string(),
+                ..Default::default()
+            }));
+        }
+        server.send(send_req("alice", "r1", "missed")).await.unwrap();
+        let status = loop {
+            match stream.next().await.unwrap() {
+                Ok(event) => assert!(!matches!(event.event, Some(chat::room_event::Event::Message(_)))),
+                Err(status) => break status,
+            }
+        };
+        assert_eq!(status.code(), tonic::Code::DataLoss);
+        assert!(stream.next().await.is_none());
+
+        // 带着最后收到的seq重新订阅即可补齐
+        let mut subscribe = join_req("bob", "r1", None);
+        subscribe.get_mut().after_seq = Some(0);
+        let mut stream = server.subscribe(subscribe).await.unwrap().into_inner();
+        assert_eq!(texts(&[next_message(&mut stream).await]), ["missed"]);
+    }
+
+    #[tokio::test]
+    async fn shutdown_closes_subscriptions_and_flushes() {
+        let dir = TempDir::new();
+        let datapath = dir.path();
+        let server = test_server(datapath);
+        server.createroom(createroom_req("alice", "r1", None, true)).await.unwrap();
+        let mut stream = server.subscribe(join_req("alice", "r1", None)).await.unwrap().into_inner();
+        server.send(send_req("alice", "r1", "a")).await.unwrap();
+        assert_eq!(texts(&[next_message(&mut stream).await]), ["a"]);
+
+        server.begin_shutdown();
+        let status = loop {
+            if let Err(status) = stream.next().await.unwrap() {
+                break status;
+            }
+        };
+        assert_eq!(status.code(), tonic::Code::Unavailable);
+        assert!(stream.next().await.is_none());
+        let status = server.subscribe(join_req("alice", "r1", None)).await.unwrap_err();
+        assert_eq!(status.code(), tonic::Code::Unavailable);
+
+        server.shutdown();
+        let snapshot = chat::Room::from_file(&roomlog::room_path(datapath, "r1")).unwrap();
+        assert_eq!(texts(&snapshot.messages), ["a"]);
+        assert!(!std::path::Path::new(&roomlog::log_path(datapath, "r1")).exists());
+    }
+
+    #[tokio::test]
+    async fn presence_per_room() {
+        let dir = TempDir::new();
+        let datapath = dir.path();
+        let server = server_with(Config {
+            presence_timeout_ms: 300,
+            ..test_config(datapath)
+        });
+        server.createroom(createroom_req("alice", "r1", None, true)).await.unwrap();
+        server.createroom(createroom_req("alice", "r2", None, true)).await.unwrap();
+        let mut stream = server.subscribe(join_req("alice", "r1", None)).await.unwrap().into_inner();
+        server.send(send_req("alice", "r1", "hi")).await.unwrap();
+
+        server.join(join_req("bob", "r1", None)).await.unwrap();
+        server.join(join_req("bob", "r2", None)).await.unwrap();
+        // opening the stream counts as activity, alice never shows up as away
+        assert_eq!(next_presence(&mut stream).await, ("alice".to_string(), chat::PresenceState::Online));
+        assert_eq!(next_presence(&mut stream).await, ("bob".to_string(), chat::PresenceState::Online));
+
+        // heartbeats in r1 do not keep bob online in r2
+        for _ in 0..15 {
+            tokio::time::sleep(std::time::Duration::from_millis(100)).await;
+            server.heartbeat(heartbeat_req("bob", "r1", 1)).await.unwrap();
+        }
+        let response = server.getrooms(authed("alice", chat::GetRoomsRequest {
+            client: Some(client("alice")),
+        })).await.unwrap().into_inner();
+        let online: Vec<(&str, &[String])> = response.rooms.iter()
+            .map(|r| (r.name.as_str(), &r.online_users[..]))
+            .collect();
+        assert_eq!(online, [("r1", &["alice".to_string(), "bob".to_string()][..]), ("r2", &[][..])]);
+
+        server.exitroom(authed("bob", chat::ExitRoomRequest {
+            client: Some(client("bob")),
+            roomname: "r1".to_string(),
+        })).await.unwrap();
+        assert_eq!(next_presence(&mut stream).await, ("bob".to_string(), chat::PresenceState::Offline));
+    }
+
+    fn direct_req(name: &str, to: &str, text: Option<&str>) -> Request<chat::SendDirectRequest> {
+        authed(name, chat::SendDirectRequest {
+            client: Some(client(name)),
+            to: to.to_string(),
+            message: text.map(|text| send_req(name, "", text).into_inner().message.unwrap()),
+        })
+    }
+
+    fn conversations_req(name: &str) -> Request<chat::ListConversationsRequest> {
+        authed(name, chat::ListConversationsRequest {
+            client: Some(client(name)),
+        })
+    }
+
+    #[tokio::test]
+    async fn direct_messages() {
+        let dir = TempDir::new();
+        let datapath = dir.path();
+        let server = test_server(datapath);
+        for name in ["alice", "bob", "carol"] {
+            server.signup(Request::new(chat::UserSignupRequest {
+                client: Some(client(name)),
+                password: USER_PASSWORD.to_string(),
+            })).await.unwrap();
+        }
+
+        let response = server.send_direct(direct_req("alice", "bob", Some("hi"))).await.unwrap().into_inner();
+        assert_eq!(response.conversations[0].peer, "bob");
+        assert_eq!(response.conversations[0].message_count, 1);
+        let roomname = response.conversations[0].roomname.clone();
+        // the same conversation from the other side
+        let response = server.send_direct(direct_req("bob", "alice", None)).await.unwrap().into_inner();
+        assert_eq!(response.conversations[0].roomname, roomname);
+        let mut stream = server.subscribe(join_req("bob", &roomname, None)).await.unwrap().into_inner();
+        assert_eq!(texts(&[next_message(&mut stream).await]), ["hi"]);
+        server.send_direct(direct_req("bob", "alice", Some("yo"))).await.unwrap();
+        assert_eq!(texts(&[next_message(&mut stream).await]), ["yo"]);
+
+        // only the two users can see or enter it
+        let status = server.join(join_req("carol", &roomname, None)).await.unwrap_err();
+        assert_eq!(status.code(), tonic::Code::PermissionDenied);
+        assert!(server.list_conversations(conversations_req("carol")).await.unwrap().into_inner().conversations.is_empty());
+        let response = server.getrooms(authed("carol", chat::GetRoomsRequest {
+            client: Some(client("carol")),
+        })).await.unwrap().into_inner();
+        assert!(response.rooms.is_empty());
+
+        let status = server.send_direct(direct_req("alice", "nobody", Some("hi"))).await.unwrap_err();
+        assert_eq!(status.code(), tonic::Code::NotFound);
+        let status = server.createroom(createroom_req("carol", &common::direct_roomname("carol", "eve"), None, true))
+            .await.unwrap_err();
+        assert_eq!(status.code(), tonic::Code::InvalidArgument);
+
+        // persisted like rooms
+        drop(stream);
+        drop(server);
+        let server = test_server(datapath);
+        let response = server.list_conversations(conversations_req("alice")).await.unwrap().into_inner();
+        assert_eq!(response.conversations.len(), 1);
+        assert_eq!(response.conversations[0].peer, "bob");
+        assert_eq!(response.conversations[0].message_count, 2);
+        assert_eq!(texts(&[response.conversations[0].last_message.clone().unwrap()]), ["yo"]);
+    }
+
+    #[tokio::test]
+    async fn server_assigns_id_and_seq() {
+        let dir = TempDir::new();
+        let datapath = dir.path();
+        let server = test_server(datapath);
+        server.createroom(createroom_req("alice", "r1", None, true)).await.unwrap();
+        for text in ["a", "b", "c"] {
+            let mut request = send_req("alice", "r1", text);
+            let message = request.get_mut().message.as_mut().unwrap();
+            message.id = "forged".to_string();
+            message.seq = 99;
+            message.time = 42;
+            server.send(request).await.unwrap();
+        }
+        let response = server.join(join_req("alice", "r1", None)).await.unwrap().into_inner();
+        let seqs: Vec<u64> = response.messages.iter().map(|m| m.seq).collect();
+        assert_eq!(seqs, [1, 2, 3]);
+        let ids: HashSet<&str> = response.messages.iter().map(|m| m.id.as_str()).collect();
+        assert_eq!(ids.len(), 3);
+        assert!(!ids.contains("forged") && !ids.contains(""));
+        assert!(response.messages.iter().all(|m| m.time > 42));
+
+        // fetches page by seq
+        let response = server.heartbeat(heartbeat_req("alice", "r1", 1)).await.unwrap().into_inner();
+        assert_eq!(texts(&response.messages), ["b", "c"]);
+        let mut subscribe = join_req("alice", "r1", None);
+        subscribe.get_mut().after_seq = Some(2);
+        let mut stream = server.subscribe(subscribe).await.unwrap().into_inner();
+        assert_eq!(next_message(&mut stream).await.seq, 3);
+    }
+
+    #[tokio::test]
+    async fn legacy_messages_get_seq() {
+        let dir = TempDir::new();
+        let datapath = dir.path();
+        let server = sqlite_server(datapath);
+        server.createroom(createroom_req("alice", "r1", None, true)).await.unwrap();
+        drop(server);
+        // messages written before ids and sequence numbers existed
+        let storage = SqliteStorage::open(datapath).unwrap();
+        let mut room = storage.load_rooms().unwrap().pop().unwrap();
+        for text in ["a", "b"] {
+            room.messages.push(send_req("alice", "r1", text).into_inner().message.unwrap());
+            storage.append_message(&room).unwrap();
+        }
+        drop(storage);
+
+        let server = sqlite_server(datapath);
+        let before = server.join(join_req("alice", "r1", None)).await.unwrap().into_inner().messages;
+        assert_eq!(before.iter().map(|m| m.seq).collect::<Vec<_>>(), [1, 2]);
+        assert!(before.iter().all(|m| !m.id.is_empty()));
+        server.send(send_req("alice", "r1", "c")).await.unwrap();
+        crash(server);
+
+        // assigned ids are persisted
+        let server = sqlite_server(datapath);
+        let after = server.join(join_req("alice", "r1", None)).await.unwrap().into_inner().messages;
+        assert_eq!(after[..2], before[..]);
+        assert_eq!(after[2].seq, 3);
+    }
+
+    #[tokio::test]
+    async fn edit_and_delete_messages() {
+        let dir = TempDir::new();
+        let datapath = dir.path();
+        let server = test_server(datapath);
+        server.createroom(createroom_req("alice", "r1", None, true)).await.unwrap();
+        server.join(join_req("bob", "r1", None)).await.unwrap();
+        let mut ids = vec![];
+        for (name, text) in [("bob", "helo"), ("bob", "spam"), ("bob", "bye")] {
+            let sent = server.send(send_req(name, "r1", text)).await.unwrap().into_inner().messages;
+            ids.push(sent[0].id.clone());
+        }
+        let mut subscribe = join_req("alice", "r1", None);
+        subscribe.get_mut().after_seq = Some(3);
+        let mut stream = server.subscribe(subscribe).await.unwrap().into_inner();
+
+        // only the author can edit
+        let err = server.edit_message(edit_req("alice", "r1", &ids[0], "hijacked")).await.unwrap_err();
+        assert_eq!(err.code(), tonic::Code::PermissionDenied);
+        let err = server.edit_message(edit_req("bob", "r1", "nope", "hello")).await.unwrap_err();
+        assert_eq!(err.code(), tonic::Code::NotFound);
+        server.edit_message(edit_req("bob", "r1", &ids[0], "hello")).await.unwrap();
+        server.edit_message(edit_req("bob", "r1", &ids[0], "hello!")).await.unwrap();
+        let updated = next_updated(&mut stream).await;
+        assert_eq!((updated.seq, updated.bytes.as_slice()), (1, &b"hello"[..]));
+        let updated = next_updated(&mut stream).await;
+        let revisions: Vec<&[u8]> = updated.revisions.iter().map(|r| r.bytes.as_slice()).collect();
+        assert_eq!(revisions, [&b"helo"[..], &b"hello"[..]]);
+        assert!(updated.modified_time > 0);
+
+        // the owner and the author can delete
+        let err = server.delete_message(delete_req("carol", "r1", &ids[1])).await.unwrap_err();
+        assert_eq!(err.code(), tonic::Code::PermissionDenied);
+        server.delete_message(delete_req("alice", "r1", &ids[1])).await.unwrap();
+        server.delete_message(delete_req("bob", "r1", &ids[0])).await.unwrap();
+        let deleted = next_updated(&mut stream).await;
+        assert!(deleted.deleted && deleted.bytes.is_empty());
+        assert_eq!(deleted.deleted_by, "alice");
+        let err = server.edit_message(edit_req("bob", "r1", &ids[0], "again")).await.unwrap_err();
+        assert_eq!(err.code(), tonic::Code::FailedPrecondition);
+        drop(stream);
+        crash(server);
+
+        // edits and tombstones survive a crash
+        let server = test_server(datapath);
+        let messages = server.join(join_req("alice", "r1", None)).await.unwrap().into_inner().messages;
+        assert_eq!(messages.iter().map(|m| m.seq).collect::<Vec<_>>(), [1, 2, 3]);
+        assert!(messages[0].deleted && messages[0].revisions.is_empty() && messages[0].bytes.is_empty());
+        assert_eq!((messages[1].deleted, messages[1].deleted_by.as_str()), (true, "alice"));
+        assert_eq!(texts(&messages[2..]), ["bye"]);
+    }
+
+    #[tokio::test]
+    async fn removed_members_cannot_delete_their_messages() {
+        let dir = TempDir::new();
+        let server = moderated_server(dir.path()).await;
+        let by_carol = server.send(send_req("carol", "r1", "mine")).await.unwrap().into_inner().messages;
+        let by_dave = server.send(send_req("dave", "r1", "mine")).await.unwrap().into_inner().messages;
+        server.kick(moderate_req("bob", "carol")).await.unwrap();
+        server.ban(moderate_req("bob", "dave")).await.unwrap();
+        let err = server.delete_message(delete_req("carol", "r1", &by_carol[0].id)).await.unwrap_err();
+        assert_eq!(err.code(), tonic::Code::PermissionDenied);
+        let err = server.delete_message(delete_req("dave", "r1", &by_dave[0].id)).await.unwrap_err();
+        assert_eq!(err.code(), tonic::Code::PermissionDenied);
+        assert!(err.message().contains("banned"));
+        let err = server.edit_message(edit_req("dave", "r1", &by_dave[0].id, "edited")).await.unwrap_err();
+        assert!(err.message().contains("banned"));
+    }
+
+    #[tokio::test]
+    async fn reply_threads() {
+        let dir = TempDir::new();
+        let datapath = dir.path();
+        let server = test_server(datapath);
+        server.createroom(createroom_req("alice", "r1", None, false)).await.unwrap();
+        let send = |name: &'static str, text: &'static str, reply_to: String| {
+            let mut request = send_req(name, "r1", text);
+            request.get_mut().message.as_mut().unwrap().reply_to = reply_to;
+            let server = &server;
+            async move { server.send(request).await.map(|r| r.into_inner().messages[0].id.clone()) }
+        };
+        let root = send("alice", "lunch?", String::new()).await.unwrap();
+        let other = send("alice", "unrelated", String::new()).await.unwrap();
+        server.join(join_req("bob", "r1", None)).await.unwrap();
+        // bob joined after the root was posted and can not see it
+        let err = send("bob", "sure", root.clone()).await.unwrap_err();
+        assert_eq!(err.code(), tonic::Code::NotFound);
+        let err = server.get_thread(thread_req("bob", "r1", &root)).await.unwrap_err();
+        assert_eq!(err.code(), tonic::Code::NotFound);
+
+        let reply = send("alice", "noodles", root.clone()).await.unwrap();
+        send("bob", "yes", reply.clone()).await.unwrap();
+        send("alice", "also unrelated", other).await.unwrap();
+        send("alice", "12:00", root.clone()).await.unwrap();
+        let thread = server.get_thread(thread_req("alice", "r1", &root)).await.unwrap().into_inner().messages;
+        assert_eq!(texts(&thread), ["lunch?", "noodles", "yes", "12:00"]);
+        assert_eq!(thread[2].reply_to, reply);
+        let thread = server.get_thread(thread_req("bob", "r1", &reply)).await.unwrap().into_inner().messages;
+        assert_eq!(texts(&thread), ["noodles", "yes"]);
+        let err = server.get_thread(thread_req("carol", "r1", &root)).await.unwrap_err();
+        assert_eq!(err.code(), tonic::Code::InvalidArgument);
+    }
+
+    #[tokio::test]
+    async fn reactions() {
+        let dir = TempDir::new();
+        let datapath = dir.path();
+        let server = test_server(datapath);
+        server.createroom(createroom_req("alice", "r1", None, true)).await.unwrap();
+        server.join(join_req("bob", "r1", None)).await.unwrap();
+        let id = server.send(send_req("alice", "r1", "shipped")).await.unwrap().into_inner().messages[0].id.clone();
+        let mut subscribe = join_req("alice", "r1", None);
+        subscribe.get_mut().after_seq = Some(1);
+        let mut stream = server.subscribe(subscribe).await.unwrap().into_inner();
+
+        for (name, emoji) in [("bob", "🎉"), ("alice", "🎉"), ("bob", "🎉"), ("bob", "👍")] {
+            server.add_reaction(reaction_req(name, "r1", &id, emoji)).await.unwrap();
+        }
+        // the duplicate is not delivered
+        for total in [1, 2, 3] {
+            let updated = next_updated(&mut stream).await;
+            assert_eq!(updated.reactions.iter().map(|r| r.usernames.len()).sum::<usize>(), total);
+        }
+        let message = server.remove_reaction(reaction_req("bob", "r1", &id, "🎉")).await.unwrap().into_inner().messages.pop().unwrap();
+        assert_eq!(message.reactions, [
+            chat::Reaction { emoji: "🎉".to_string(), usernames: vec!["alice".to_string()] },
+            chat::Reaction { emoji: "👍".to_string(), usernames: vec!["bob".to_string()] },
+        ]);
+        assert_eq!(next_updated(&mut stream).await, message);
+        assert!(format!("{}", message).ends_with("  🎉 1  👍 1"));
+
+        let err = server.add_reaction(reaction_req("carol", "r1", &id, "🎉")).await.unwrap_err();
+        assert_eq!(err.code(), tonic::Code::InvalidArgument);
+        let err = server.add_reaction(reaction_req("bob", "r1", &id, "")).await.unwrap_err();
+        assert_eq!(err.code(), tonic::Code::InvalidArgument);
+        let err = server.add_reaction(reaction_req("bob", "r1", "nope", "🎉")).await.unwrap_err();
+        assert_eq!(err.code(), tonic::Code::NotFound);
+        drop(stream);
+        crash(server);
+
+        let server = test_server(datapath);
+        let messages = server.join(join_req("bob", "r1", None)).await.unwrap().into_inner().messages;
+        assert_eq!(messages, [message]);
+    }
+
+    // 入会给3条，每页最多4条
+    fn paged_config(datapath: &str) -> Config {
+        let mut config = test_config(datapath);
+        config.history.join_limit = 3;
+        config.history.page_limit = 4;
+        config
+    }
+
+    async fn paged_server(datapath: &str) -> MyChatServer<FileStorage> {
+        let server = server_with(paged_config(datapath));
+        post_pages(&server).await;
+        server
+    }
+
+    // alice发了1..=10，bob在5之后加入
+    async fn post_pages<S: Storage>(server: &MyChatServer<S>) {
+        server.createroom(createroom_req("alice", "r1", None, false)).await.unwrap();
+        for i in 1..=5 {
+            server.send(send_req("alice", "r1", &i.to_string())).await.unwrap();
+        }
+        server.join(join_req("bob", "r1", None)).await.unwrap();
+        for i in 6..=10 {
+            server.send(send_req("alice", "r1", &i.to_string())).await.unwrap();
+        }
+    }
+
+    fn history_req(name: &str, before_seq: u64, limit: u32) -> Request<chat::GetHistoryRequest> {
+        authed(name, chat::GetHistoryRequest {
+            client: Some(client(name)),
+            roomname: "r1".to_string(),
+            before_seq,
+            limit,
+        })
+    }
+
+    #[tokio::test]
+    async fn join_returns_latest_page() {
+        let dir = TempDir::new();
+        let server = paged_server(dir.path()).await;
+        let response = server.join(join_req("alice", "r1", None)).await.unwrap().into_inner();
+        assert_eq!(texts(&response.messages), ["8", "9", "10"]);
+        assert!(response.more_history);
+    }
+
+    #[tokio::test]
+    async fn get_history_pages_backwards() {
+        let dir = TempDir::new();
+        let server = paged_server(dir.path()).await;
+        // page_limit caps the page
+        let response = server.get_history(history_req("alice", 8, 0)).await.unwrap().into_inner();
+        assert_eq!(texts(&response.messages), ["4", "5", "6", "7"]);
+        assert!(response.more_history);
+        let response = server.get_history(history_req("alice", 4, 10)).await.unwrap().into_inner();
+        assert_eq!(texts(&response.messages), ["1", "2", "3"]);
+        assert!(!response.more_history);
+        assert!(server.get_history(history_req("alice", 1, 2)).await.unwrap().into_inner().messages.is_empty());
+    }
+
+    #[tokio::test]
+    async fn get_history_hides_messages_before_join() {
+        let dir = TempDir::new();
+        let server = paged_server(dir.path()).await;
+        let response = server.get_history(history_req("bob", 8, 2)).await.unwrap().into_inner();
+        assert_eq!(texts(&response.messages), ["6", "7"]);
+        assert!(!response.more_history);
+        let err = server.get_history(history_req("carol", 0, 2)).await.unwrap_err();
+        assert_eq!(err.code(), tonic::Code::InvalidArgument);
+    }
+
+    #[tokio::test]
+    async fn get_history_pages_through_sqlite() {
+        let dir = TempDir::new();
+        let server = MyChatServer::new(paged_config(dir.path()), SqliteStorage::open(dir.path()).unwrap());
+        server.init().unwrap();
+        post_pages(&server).await;
+        let response = server.get_history(history_req("alice", 8, 0)).await.unwrap().into_inner();
+        assert_eq!(texts(&response.messages), ["4", "5", "6", "7"]);
+        assert!(response.more_history);
+        let response = server.get_history(history_req("bob", 8, 10)).await.unwrap().into_inner();
+        assert_eq!(texts(&response.messages), ["6", "7"]);
+        assert!(!response.more_history);
+        // an edit is read back from the table
+        let id = response.messages[0].id.clone();
+        server.edit_message(edit_req("alice", "r1", &id, "six")).await.unwrap();
+        let response = server.get_history(history_req("bob", 7, 1)).await.unwrap().into_inner();
+        assert_eq!(texts(&response.messages), ["six"]);
+    }
+
+    #[tokio::test]
+    async fn heartbeat_catches_up_one_page_at_a_time() {
+        let dir = TempDir::new();
+        let server = paged_server(dir.path()).await;
+        let response = server.heartbeat(heartbeat_req("alice", "r1", 0)).await.unwrap().into_inner();
+        assert_eq!(texts(&response.messages), ["1", "2", "3", "4"]);
+        let response = server.heartbeat(heartbeat_req("alice", "r1", 4)).await.unwrap().into_inner();
+        assert_eq!(texts(&response.messages), ["5", "6", "7", "8"]);
+    }
+
+    #[tokio::test]
+    async fn subscribe_catches_up_newest_page() {
+        let dir = TempDir::new();
+        let server = paged_server(dir.path()).await;
+        let mut stream = server.subscribe(subscribe_req("alice", "r1", 0)).await.unwrap().into_inner();
+        server.send(send_req("bob", "r1", "11")).await.unwrap();
+        let mut caught_up = vec![];
+        for _ in 0..5 {
+            caught_up.push(next_message(&mut stream).await);
+        }
+        assert_eq!(texts(&caught_up), ["7", "8", "9", "10", "11"]);
+        // the rest is one get_history away
+        let response = server.get_history(history_req("alice", caught_up[0].seq, 0)).await.unwrap().into_inner();
+        assert_eq!(texts(&response.messages), ["3", "4", "5", "6"]);
+    }
+
+    fn moderate_req(name: &str, target: &str) -> Request<chat::ModerateRequest> {
+        authed(name, chat::ModerateRequest {
+            client: Some(client(name)),
+            roomname: "r1".to_string(),
+            username: target.to_string(),
+        })
+    }
+
+    fn code(result: Result<Response<chat::ServerResponse>, Status>) -> tonic::Code {
+        result.unwrap_err().code()
+    }
+
+    // alice是房主，bob是管理员，carol和dave是普通成员
+    async fn moderated_server(datapath: &str) -> MyChatServer<FileStorage> {
+        let server = test_server(datapath);
+        server.createroom(createroom_req("alice", "r1", None, true)).await.unwrap();
+        for name in ["bob", "carol", "dave"] {
+            server.join(join_req(name, "r1", None)).await.unwrap();
+        }
+        server.promote(moderate_req("alice", "bob")).await.unwrap();
+        server
+    }
+
+    #[tokio::test]
+    async fn only_owner_changes_roles() {
+        let dir = TempDir::new();
+        let server = moderated_server(dir.path()).await;
+        assert_eq!(code(server.promote(moderate_req("bob", "carol")).await), tonic::Code::PermissionDenied);
+        assert_eq!(code(server.promote(moderate_req("alice", "bob")).await), tonic::Code::FailedPrecondition);
+        server.demote(moderate_req("alice", "bob")).await.unwrap();
+        assert_eq!(code(server.kick(moderate_req("bob", "dave")).await), tonic::Code::PermissionDenied);
+    }
+
+    #[tokio::test]
+    async fn moderators_cannot_touch_owner_or_moderators() {
+        let dir = TempDir::new();
+        let server = moderated_server(dir.path()).await;
+        server.promote(moderate_req("alice", "carol")).await.unwrap();
+        assert_eq!(code(server.kick(moderate_req("bob", "carol")).await), tonic::Code::PermissionDenied);
+        assert_eq!(code(server.ban(moderate_req("bob", "alice")).await), tonic::Code::PermissionDenied);
+        server.demote(moderate_req("alice", "carol")).await.unwrap();
+        server.kick(moderate_req("bob", "carol")).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn kick_ends_subscription() {
+        let dir = TempDir::new();
+        let server = moderated_server(dir.path()).await;
+        let mut subscribe = join_req("dave", "r1", None);
+        subscribe.get_mut().after_seq = Some(0);
+        let mut stream = server.subscribe(subscribe).await.unwrap().into_inner();
+        server.kick(moderate_req("bob", "dave")).await.unwrap();
+        // the kicked user is told and the stream ends
+        loop {
+            match stream.next().await.unwrap() {
+                Ok(chat::RoomEvent { event: Some(chat::room_event::Event::Member(change)) }) => {
+                    assert_eq!((change.username.as_str(), change.role(), change.by.as_str()), ("dave", chat::Role::NotMember, "bob"));
+                    break;
+                },
+                Ok(_) => continue,
+                Err(status) => panic!("{:?}", status),
+            }
+        }
+        assert_eq!(stream.next().await.unwrap().unwrap_err().code(), tonic::Code::PermissionDenied);
+        assert!(stream.next().await.is_none());
+        assert_eq!(code(server.send(send_req("dave", "r1", "hi")).await), tonic::Code::InvalidArgument);
+        // kicked users may come back
+        server.join(join_req("dave", "r1", None)).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn ban_blocks_rejoin_and_send() {
+        let dir = TempDir::new();
+        let server = moderated_server(dir.path()).await;
+        server.ban(moderate_req("bob", "dave")).await.unwrap();
+        assert_eq!(code(server.join(join_req("dave", "r1", None)).await), tonic::Code::PermissionDenied);
+        assert_eq!(code(server.send(send_req("dave", "r1", "hi")).await), tonic::Code::PermissionDenied);
+        assert_eq!(code(server.heartbeat(heartbeat_req("dave", "r1", 0)).await), tonic::Code::PermissionDenied);
+        assert_eq!(code(server.kick(moderate_req("bob", "dave")).await), tonic::Code::FailedPrecondition);
+        server.unban(moderate_req("bob", "dave")).await.unwrap();
+        server.join(join_req("dave", "r1", None)).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn moderators_delete_member_messages() {
+        let dir = TempDir::new();
+        let server = moderated_server(dir.path()).await;
+        let sent = server.send(send_req("carol", "r1", "spam")).await.unwrap().into_inner().messages;
+        server.delete_message(delete_req("bob", "r1", &sent[0].id)).await.unwrap();
+        let response = server.join(join_req("alice", "r1", None)).await.unwrap().into_inner();
+        assert!(texts(&response.messages).iter().all(|text| text != "spam"));
+    }
+
+    #[tokio::test]
+    async fn roles_survive_crash() {
+        let dir = TempDir::new();
+        let server = moderated_server(dir.path()).await;
+        server.ban(moderate_req("bob", "dave")).await.unwrap();
+        crash(server);
+        let server = test_server(dir.path());
+        assert_eq!(code(server.join(join_req("dave", "r1", None)).await), tonic::Code::PermissionDenied);
+        server.kick(moderate_req("bob", "carol")).await.unwrap();
+        server.unban(moderate_req("bob", "dave")).await.unwrap();
+        server.join(join_req("dave", "r1", None)).await.unwrap();
+    }
+
+    async fn next_room_change(stream: &mut ReceiverStream<Result<chat::RoomEvent, Status>>) -> chat::RoomChange {
+        loop {
+            if let Some(chat::room_event::Event::Room(change)) = stream.next().await.unwrap().unwrap().event {
+                return change;
+            }
+        }
+    }
+
+    fn subscribe_req(name: &str, roomname: &str, after_seq: u64) -> Request<chat::JoinRequest> {
+        let mut request = join_req(name, roomname, None);
+        request.get_mut().after_seq = Some(after_seq);
+        request
+    }
+
+    fn update_req(name: &str, roomname: &str, password: Option<&str>, history_visible: Option<bool>) -> Request<chat::UpdateRoomRequest> {
+        authed(name, chat::UpdateRoomRequest {
+            client: Some(client(name)),
+            roomname: roomname.to_string(),
+            password: password.map(|p| p.to_string()),
+            history_visible,
+            invite_only: None,
+        })
+    }
+
+    fn rename_req(name: &str, roomname: &str, new_name: &str) -> Request<chat::RenameRoomRequest> {
+        authed(name, chat::RenameRoomRequest {
+            client: Some(client(name)),
+            roomname: roomname.to_string(),
+            new_name: new_name.to_string(),
+        })
+    }
+
+    fn transfer_req(name: &str, roomname: &str, new_owner: &str) -> Request<chat::TransferOwnershipRequest> {
+        authed(name, chat::TransferOwnershipRequest {
+            client: Some(client(name)),
+            roomname: roomname.to_string(),
+            new_owner: new_owner.to_string(),
+        })
+    }
+
+    fn delete_room_req(name: &str, roomname: &str) -> Request<chat::DeleteRoomRequest> {
+        authed(name, chat::DeleteRoomRequest {
+            client: Some(client(name)),
+            roomname: roomname.to_string(),
+        })
+    }
+
+    fn room_names(response: &chat::ServerResponse) -> Vec<&str> {
+        response.rooms.iter().map(|r| r.name.as_str()).collect()
+    }
+
+    // alice创建r1并发了一条信息，之后bob加入
+    async fn lifecycle_server(datapath: &str) -> MyChatServer<FileStorage> {
+        let server = test_server(datapath);
+        server.createroom(createroom_req("alice", "r1", None, true)).await.unwrap();
+        server.send(send_req("alice", "r1", "before bob")).await.unwrap();
+        server.join(join_req("bob", "r1", None)).await.unwrap();
+        server
+    }
+
+    #[tokio::test]
+    async fn only_owner_manages_room() {
+        let dir = TempDir::new();
+        let server = lifecycle_server(dir.path()).await;
+        assert_eq!(code(server.update_room(update_req("bob", "r1", Some("pw"), None)).await), tonic::Code::PermissionDenied);
+        assert_eq!(code(server.rename_room(rename_req("bob", "r1", "r2")).await), tonic::Code::PermissionDenied);
+        assert_eq!(code(server.delete_room(delete_room_req("bob", "r1")).await), tonic::Code::PermissionDenied);
+        assert_eq!(code(server.transfer_ownership(transfer_req("bob", "r1", "alice")).await), tonic::Code::PermissionDenied);
+        // an update has to change something
+        assert_eq!(code(server.update_room(update_req("alice", "r1", None, None)).await), tonic::Code::InvalidArgument);
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn concurrent_create_and_rename_claim_a_name_once() {
+        let dir = TempDir::new();
+        let server = Arc::new(lifecycle_server(dir.path()).await);
+        for round in 0..50 {
+            let name = format!("taken{}", round);
+            let mut tasks = vec![];
+            for i in 0..8 {
+                let (server, name) = (Arc::clone(&server), name.clone());
+                tasks.push(tokio::spawn(async move {
+                    if i == 0 && round == 0 {
+                        server.rename_room(rename_req("alice", "r1", &name)).await.is_ok()
+                    } else {
+                        server.createroom(createroom_req("alice", &name, None, true)).await.is_ok()
+                    }
+                }));
+            }
+            let mut claimed = 0;
+            for task in tasks {
+                claimed += task.await.unwrap() as usize;
+            }
+            assert_eq!(claimed, 1, "{}", name);
+        }
+        let response = server.getrooms(authed("alice", chat::GetRoomsRequest {
+            client: Some(client("alice")),
+        })).await.unwrap().into_inner();
+        let names = room_names(&response);
+        assert_eq!(names.iter().collect::<HashSet<_>>().len(), names.len());
+    }
+
+    #[tokio::test]
+    async fn update_room_settings() {
+        let dir = TempDir::new();
+        let server = lifecycle_server(dir.path()).await;
+        let mut stream = server.subscribe(subscribe_req("bob", "r1", 1)).await.unwrap().into_inner();
+        server.update_room(update_req("alice", "r1", Some(ROOM_PASSWORD), Some(false))).await.unwrap();
+        let change = next_room_change(&mut stream).await;
+        let summary = change.room.unwrap();
+        assert_eq!((change.roomname.as_str(), change.by.as_str()), ("r1", "alice"));
+        assert!(summary.has_password && !summary.history_visible);
+        let response = server.join(join_req("carol", "r1", None)).await.unwrap().into_inner();
+        assert_eq!(response.code, chat::ResponseCode::RoomPasswordWrong as i32);
+        let response = server.join(join_req("carol", "r1", Some(ROOM_PASSWORD))).await.unwrap().into_inner();
+        assert!(response.messages.is_empty());
+    }
+
+    #[tokio::test]
+    async fn rename_keeps_members_history_and_subscriptions() {
+        let dir = TempDir::new();
+        let server = lifecycle_server(dir.path()).await;
+        let mut stream = server.subscribe(subscribe_req("bob", "r1", 1)).await.unwrap().into_inner();
+        server.createroom(createroom_req("dave", "taken", None, true)).await.unwrap();
+        assert_eq!(code(server.rename_room(rename_req("alice", "r1", "taken")).await), tonic::Code::AlreadyExists);
+        for invalid in ["", "../r2", ".hidden", "dm:alice:bob"] {
+            assert_eq!(code(server.rename_room(rename_req("alice", "r1", invalid)).await), tonic::Code::InvalidArgument, "{}", invalid);
+        }
+        server.rename_room(rename_req("alice", "r1", "lobby")).await.unwrap();
+        let change = next_room_change(&mut stream).await;
+        assert_eq!((change.roomname.as_str(), change.room.unwrap().name.as_str()), ("r1", "lobby"));
+        assert_eq!(code(server.send(send_req("bob", "r1", "hi")).await), tonic::Code::InvalidArgument);
+        server.send(send_req("bob", "lobby", "hi")).await.unwrap();
+        assert_eq!(texts(&[next_message(&mut stream).await]), ["hi"]);
+        let found = server.search(authed("alice", chat::SearchRequest {
+            client: Some(client("alice")),
+            roomname: "lobby".to_string(),
+            query: "bob".to_string(),
+            ..Default::default()
+        })).await.unwrap().into_inner().messages;
+        assert_eq!(texts(&found), ["before bob"]);
+    }
+
+    #[tokio::test]
+    async fn transfer_keeps_old_owner_as_moderator() {
+        let dir = TempDir::new();
+        let server = lifecycle_server(dir.path()).await;
+        server.join(join_req("carol", "r1", None)).await.unwrap();
+        let mut stream = server.subscribe(subscribe_req("bob", "r1", 1)).await.unwrap().into_inner();
+        assert_eq!(code(server.transfer_ownership(transfer_req("alice", "r1", "erin")).await), tonic::Code::FailedPrecondition);
+        server.transfer_ownership(transfer_req("alice", "r1", "bob")).await.unwrap();
+        assert_eq!(next_room_change(&mut stream).await.room.unwrap().manner, "bob");
+        assert_eq!(code(server.update_room(update_req("alice", "r1", Some(""), None)).await), tonic::Code::PermissionDenied);
+        server.kick(moderate_req("alice", "carol")).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn room_changes_survive_crash() {
+        let dir = TempDir::new();
+        let server = lifecycle_server(dir.path()).await;
+        server.rename_room(rename_req("alice", "r1", "lobby")).await.unwrap();
+        server.transfer_ownership(transfer_req("alice", "lobby", "bob")).await.unwrap();
+        server.update_room(update_req("bob", "lobby", Some(ROOM_PASSWORD), None)).await.unwrap();
+        crash(server);
+        let server = test_server(dir.path());
+        let response = server.join(join_req("bob", "lobby", None)).await.unwrap().into_inner();
+        assert_eq!(texts(&response.messages), ["before bob"]);
+        let response = server.getrooms(authed("alice", chat::GetRoomsRequest {
+            client: Some(client("alice")),
+        })).await.unwrap().into_inner();
+        assert_eq!(room_names(&response), ["lobby"]);
+        assert!(response.rooms[0].has_password);
+        assert_eq!(response.rooms[0].manner, "bob");
+    }
+
+    #[tokio::test]
+    async fn delete_room_ends_subscriptions_and_removes_files() {
+        let dir = TempDir::new();
+        let server = lifecycle_server(dir.path()).await;
+        server.createroom(createroom_req("dave", "taken", None, true)).await.unwrap();
+        let mut stream = server.subscribe(subscribe_req("bob", "r1", 1)).await.unwrap().into_inner();
+        server.delete_room(delete_room_req("alice", "r1")).await.unwrap();
+        let change = next_room_change(&mut stream).await;
+        assert!(change.deleted && change.room.is_none());
+        assert_eq!(stream.next().await.unwrap().unwrap_err().code(), tonic::Code::NotFound);
+        assert!(stream.next().await.is_none());
+        assert_eq!(code(server.send(send_req("bob", "r1", "hi")).await), tonic::Code::InvalidArgument);
+        drop(server);
+        let server = test_server(dir.path());
+        let response = server.getrooms(authed("alice", chat::GetRoomsRequest {
+            client: Some(client("alice")),
+        })).await.unwrap().into_inner();
+        assert_eq!(room_names(&response), ["taken"]);
+    }
+
+    fn create_invite_req(name: &str, uses: u32, ttl_secs: u64) -> Request<chat::CreateInviteRequest> {
+        authed(name, chat::CreateInviteRequest {
+            client: Some(client(name)),
+            roomname: "r1".to_string(),
+            uses,
+            ttl_secs,
+        })
+    }
+
+    fn list_invites_req(name: &str) -> Request<chat::ListInvitesRequest> {
+        authed(name, chat::ListInvitesRequest {
+            client: Some(client(name)),
+            roomname: "r1".to_string(),
+        })
+    }
+
+    fn revoke_invite_req(name: &str, code: &str) -> Request<chat::RevokeInviteRequest> {
+        authed(name, chat::RevokeInviteRequest {
+            client: Some(client(name)),
+            roomname: "r1".to_string(),
+            code: code.to_string(),
+        })
+    }
+
+    fn invited_req(name: &str, code: &str) -> Request<chat::JoinRequest> {
+        let mut request = join_req(name, "r1", None);
+        request.get_mut().invite_code = Some(code.to_string());
+        request
+    }
+
+    async fn create_invite(server: &MyChatServer<FileStorage>, name: &str, uses: u32, ttl_secs: u64) -> chat::Invite {
+        server.create_invite(create_invite_req(name, uses, ttl_secs)).await.unwrap().into_inner().invites.remove(0)
+    }
+
+    // alice的r1有密码且只能凭邀请码加入，bob是普通成员
+    async fn invite_only_server(datapath: &str) -> MyChatServer<FileStorage> {
+        let server = test_server(datapath);
+        server.createroom(createroom_req("alice", "r1", Some(ROOM_PASSWORD), true)).await.unwrap();
+        server.join(join_req("bob", "r1", Some(ROOM_PASSWORD))).await.unwrap();
+        server.update_room(authed("alice", chat::UpdateRoomRequest {
+            client: Some(client("alice")),
+            roomname: "r1".to_string(),
+            invite_only: Some(true),
+            ..Default::default()
+        })).await.unwrap();
+        server
+    }
+
+    #[tokio::test]
+    async fn invite_only_rejects_password_and_members_cannot_invite() {
+        let dir = TempDir::new();
+        let server = invite_only_server(dir.path()).await;
+        assert_eq!(code(server.create_invite(create_invite_req("bob", 1, 0)).await), tonic::Code::PermissionDenied);
+        assert_eq!(code(server.list_invites(list_invites_req("bob")).await), tonic::Code::PermissionDenied);
+        assert_eq!(code(server.join(join_req("carol", "r1", Some(ROOM_PASSWORD))).await), tonic::Code::PermissionDenied);
+        assert_eq!(code(server.create_invite(create_invite_req("alice", MAX_INVITE_USES + 1, 0)).await), tonic::Code::InvalidArgument);
+        assert_eq!(code(server.create_invite(create_invite_req("alice", 1, MAX_INVITE_TTL_SECS + 1)).await), tonic::Code::InvalidArgument);
+    }
+
+    #[tokio::test]
+    async fn single_use_invite() {
+        let dir = TempDir::new();
+        let server = invite_only_server(dir.path()).await;
+        let single = create_invite(&server, "alice", 0, 0).await;
+        assert_eq!((single.uses_left, single.by.as_str()), (1, "alice"));
+        server.join(invited_req("carol", &single.code)).await.unwrap();
+        assert_eq!(code(server.join(invited_req("dave", &single.code)).await), tonic::Code::PermissionDenied);
+        // members come back without a code
+        server.join(join_req("carol", "r1", None)).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn moderator_invites_survive_crash() {
+        let dir = TempDir::new();
+        let server = invite_only_server(dir.path()).await;
+        server.promote(moderate_req("alice", "bob")).await.unwrap();
+        let multi = create_invite(&server, "bob", 2, 3600).await;
+        let other = create_invite(&server, "bob", 5, 3600).await;
+        server.join(invited_req("dave", &multi.code)).await.unwrap();
+        crash(server);
+        let server = test_server(dir.path());
+        let invites = server.list_invites(list_invites_req("alice")).await.unwrap().into_inner().invites;
+        let left: Vec<(&str, u32)> = invites.iter().map(|i| (i.code.as_str(), i.uses_left)).collect();
+        assert_eq!(left, [(multi.code.as_str(), 1), (other.code.as_str(), 5)]);
+    }
+
+    #[tokio::test]
+    async fn revoked_and_expired_invites_are_refused() {
+        let dir = TempDir::new();
+        let server = invite_only_server(dir.path()).await;
+        let expiring = create_invite(&server, "alice", 2, 3600).await;
+        let doomed = create_invite(&server, "alice", 5, 3600).await;
+        let remaining = server.revoke_invite(revoke_invite_req("alice", &doomed.code)).await.unwrap().into_inner().invites;
+        assert_eq!(remaining.len(), 1);
+        assert_eq!(code(server.revoke_invite(revoke_invite_req("alice", &doomed.code)).await), tonic::Code::NotFound);
+        assert_eq!(code(server.join(invited_req("erin", &doomed.code)).await), tonic::Code::PermissionDenied);
+        server.state.read().unwrap().rooms[0].write().unwrap().invites[0].expires = common::now_milli_seconds();
+        assert_eq!(code(server.join(invited_req("erin", &expiring.code)).await), tonic::Code::PermissionDenied);
+        assert!(server.list_invites(list_invites_req("alice")).await.unwrap().into_inner().invites.is_empty());
+    }
+
+    // 每条最多10字节；每个用户2条，每个房间3条，之后几乎不再补充
+    async fn limited_server(datapath: &str) -> MyChatServer<FileStorage> {
+        let limits = LimitsConfig {
+            max_message_bytes: 10,
+            user_burst: 2,
+            user_per_sec: 0.01,
+            room_burst: 3,
+            room_per_sec: 0.01,
+        };
+        let server = server_with(Config { limits, ..test_config(datapath) });
+        for name in ["alice", "bob"] {
+            server.signup(Request::new(chat::UserSignupRequest {
+                client: Some(client(name)),
+                password: USER_PASSWORD.to_string(),
+            })).await.unwrap();
+        }
+        server.createroom(createroom_req("alice", "r1", None, true)).await.unwrap();
+        server.join(join_req("bob", "r1", None)).await.unwrap();
+        server
+    }
+
+    #[tokio::test]
+    async fn oversized_messages_are_refused() {
+        let dir = TempDir::new();
+        let server = limited_server(dir.path()).await;
+        let err = server.send(send_req("alice", "r1", "far too long")).await.unwrap_err();
+        assert_eq!(err.code(), tonic::Code::InvalidArgument);
+        let sent = server.send(send_req("alice", "r1", "hi")).await.unwrap().into_inner().messages;
+        let err = server.edit_message(edit_req("alice", "r1", &sent[0].id, "far too long")).await.unwrap_err();
+        assert_eq!(err.code(), tonic::Code::InvalidArgument);
+        // neither used up the bucket
+        server.send(send_req("alice", "r1", "hi again")).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn user_limit_hints_when_to_retry() {
+        let dir = TempDir::new();
+        let server = limited_server(dir.path()).await;
+        server.send(send_req("alice", "r1", "hi")).await.unwrap();
+        server.send(send_req("alice", "r1", "hi again")).await.unwrap();
+        let err = server.send(send_req("alice", "r1", "more")).await.unwrap_err();
+        assert_eq!(err.code(), tonic::Code::ResourceExhausted);
+        let retry_after: u64 = err.metadata().get(common::RETRY_AFTER_METADATA).unwrap().to_str().unwrap().parse().unwrap();
+        assert!(retry_after > 90_000 && retry_after <= 100_000, "{}", retry_after);
+        // direct messages share the user's bucket
+        let err = server.send_direct(direct_req("alice", "bob", Some("psst"))).await.unwrap_err();
+        assert_eq!(err.code(), tonic::Code::ResourceExhausted);
+        server.send_direct(direct_req("bob", "alice", Some("hey"))).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn room_limit_applies_to_every_sender() {
+        let dir = TempDir::new();
+        let server = limited_server(dir.path()).await;
+        server.send(send_req("alice", "r1", "hi")).await.unwrap();
+        server.send(send_req("alice", "r1", "hi again")).await.unwrap();
+        server.send(send_req("bob", "r1", "yo")).await.unwrap();
+        let err = server.send(send_req("bob", "r1", "yo")).await.unwrap_err();
+        assert_eq!(err.code(), tonic::Code::ResourceExhausted);
+        server.send_direct(direct_req("bob", "alice", Some("hey"))).await.unwrap();
+        let messages = server.join(join_req("alice", "r1", None)).await.unwrap().into_inner().messages;
+        assert_eq!(texts(&messages), ["hi", "hi again", "yo"]);
+    }
+
+    #[tokio::test]
+    async fn retry_hint_reaches_the_client() {
+        let dir = TempDir::new();
+        let mut channel = serve(limited_server(dir.path()).await).await;
+        // 已注册的用户再次signup即登录
+        let token = signup(&mut channel, "alice").await;
+        channel.send(over(&token, send_req("alice", "r1", "hi"))).await.unwrap();
+        channel.send(over(&token, send_req("alice", "r1", "hi"))).await.unwrap();
+        // the retry hint makes it through the transport
+        let err = channel.send(over(&token, send_req("alice", "r1", "hi"))).await.unwrap_err();
+        assert!(crate::client::clib::retry_after(&err).is_some());
+    }
+
+    fn search_req(name: &str, query: &str, author: Option<&str>, since: u64) -> Request<chat::SearchRequest> {
+        authed(name, chat::SearchRequest {
+            client: Some(client(name)),
+            roomname: "r1".to_string(),
+            query: query.to_string(),
+            author: author.map(|a| a.to_string()),
+            since,
+            ..Default::default()
+        })
+    }
+
+    #[tokio::test]
+    async fn search_filters() {
+        let dir = TempDir::new();
+        let server = test_server(dir.path());
+        server.createroom(createroom_req("alice", "r1", None, false)).await.unwrap();
+        server.send(send_req("alice", "r1", "the deploy failed")).await.unwrap();
+        server.join(join_req("bob", "r1", None)).await.unwrap();
+        server.send(send_req("bob", "r1", "retry the Deploy?")).await.unwrap();
+        tokio::time::sleep(std::time::Duration::from_millis(5)).await;
+        let last = server.send(send_req("alice", "r1", "deploy done")).await.unwrap().into_inner().messages;
+
+        let found = server.search(search_req("alice", "deploy", None, 0)).await.unwrap().into_inner().messages;
+        assert_eq!(texts(&found), ["deploy done", "retry the Deploy?", "the deploy failed"]);
+        let found = server.search(search_req("alice", "deploy", Some("alice"), 0)).await.unwrap().into_inner().messages;
+        assert_eq!(texts(&found), ["deploy done", "the deploy failed"]);
+        let found = server.search(search_req("alice", "deploy", None, last[0].time)).await.unwrap().into_inner().messages;
+        assert_eq!(texts(&found), ["deploy done"]);
+        // history before bob joined is hidden from him
+        let found = server.search(search_req("bob", "deploy", None, 0)).await.unwrap().into_inner().messages;
+        assert_eq!(texts(&found), ["deploy done", "retry the Deploy?"]);
+    }
+
+    #[tokio::test]
+    async fn search_rejects_outsiders_and_blank_queries() {
+        let dir = TempDir::new();
+        let server = test_server(dir.path());
+        server.createroom(createroom_req("alice", "r1", None, false)).await.unwrap();
+        let err = server.search(search_req("carol", "deploy", None, 0)).await.unwrap_err();
+        assert_eq!(err.code(), tonic::Code::InvalidArgument);
+        let err = server.search(search_req("alice", " ", None, 0)).await.unwrap_err();
+        assert_eq!(err.code(), tonic::Code::InvalidArgument);
+    }
+
+    #[tokio::test]
+    async fn search_index_follows_edits_and_restart() {
+        let dir = TempDir::new();
+        let server = test_server(dir.path());
+        server.createroom(createroom_req("alice", "r1", None, false)).await.unwrap();
+        server.send(send_req("alice", "r1", "the deploy failed")).await.unwrap();
+        let last = server.send(send_req("alice", "r1", "deploy done")).await.unwrap().into_inner().messages;
+        server.edit_message(edit_req("alice", "r1", &last[0].id, "rollout done")).await.unwrap();
+        crash(server);
+        let server = test_server(dir.path());
+        let found = server.search(search_req("alice", "done", None, 0)).await.unwrap().into_inner().messages;
+        assert_eq!(texts(&found), ["rollout done"]);
+        assert_eq!(texts(&server.search(search_req("alice", "deploy", None, 0)).await.unwrap().into_inner().messages),
+            ["the deploy failed"]);
+    }
+
+    fn upload_chunks(filename: &str, content: &[u8], chunk: usize) -> Vec<Result<chat::UploadRequest, Status>> {
+        content.chunks(chunk).map(|data| Ok(chat::UploadRequest {
+            filename: filename.to_string(),
+            size: content.len() as u64,
+            data: data.to_vec(),
+            ..Default::default()
+        })).collect()
+    }
+
+    #[tokio::test]
+    async fn upload_and_download_files() {
+        let dir = TempDir::new();
+        let datapath = dir.path();
+        let server = server_with(Config { max_upload_bytes: 1000, ..test_config(datapath) });
+        server.createroom(createroom_req("alice", "r1", None, false)).await.unwrap();
+        let content: Vec<u8> = (0..800).map(|i| (i % 251) as u8).collect();
+
+        let blob = server.receive_upload("alice", tokio_stream::iter(upload_chunks("cat.png", &content, 300))).await.unwrap();
+        assert_eq!((blob.size, blob.filename.as_str()), (800, "cat.png"));
+        let err = server.receive_upload("alice", tokio_stream::iter(upload_chunks("big.mp4", &[0; 1001], 300))).await.unwrap_err();
+        assert_eq!(err.code(), tonic::Code::InvalidArgument);
+        // a lying size is caught while streaming
+        let mut chunks = upload_chunks("big.mp4", &[0; 1200], 400);
+        chunks[0].as_mut().unwrap().size = 10;
+        assert!(server.receive_upload("alice", tokio_stream::iter(chunks)).await.is_err());
+        assert_eq!(std::fs::read_dir(format!("{}/{}", datapath, crate::server::blob::BLOB_DIR)).unwrap().count(), 1);
+
+        let send_blob = |name: &str, blob: chat::BlobRef| {
+            let mut request = send_req(name, "r1", "");
+            let message = request.get_mut().message.as_mut().unwrap();
+            message.msg_type = chat::MessageType::Image as i32;
+            message.blob = Some(blob);
+            request
+        };
+        let forged = chat::BlobRef { hash: "0".repeat(64), ..blob.clone() };
+        let err = server.send(send_blob("alice", forged)).await.unwrap_err();
+        assert_eq!(err.code(), tonic::Code::NotFound);
+        let sent = server.send(send_blob("alice", chat::BlobRef { size: 1, ..blob.clone() })).await.unwrap().into_inner().messages;
+        assert_eq!(sent[0].blob, Some(blob));
+        assert!(format!("{}", sent[0]).contains("[image cat.png, 800 B]"));
+
+        let download = |name: &str| authed(name, chat::DownloadRequest {
+            client: Some(client(name)),
+            roomname: "r1".to_string(),
+            id: sent[0].id.clone(),
+        });
+        let chunks: Vec<chat::BlobChunk> = server.download(download("alice")).await.unwrap().into_inner()
+            .map(|c| c.unwrap()).collect().await;
+        assert_eq!(chunks.iter().flat_map(|c| c.data.clone()).collect::<Vec<u8>>(), content);
+        // bob joined later and can not see the message
+        server.join(join_req("bob", "r1", None)).await.unwrap();
+        let err = server.download(download("bob")).await.unwrap_err();
+        assert_eq!(err.code(), tonic::Code::NotFound);
+
+        // binary text renders without panicking
+        let binary = chat::Message { bytes: vec![0xff, 0xfe, 0x00], ..sent[0].clone() };
+        assert!(format!("{}", binary).contains('\u{fffd}'));
+        // so does a message without an author
+        let anonymous = chat::Message { client: None, ..sent[0].clone() };
+        assert!(format!("{}", anonymous).contains(": "));
+        assert_eq!(chat::Client::default().username(), "");
+    }
+
+    #[tokio::test]
+    async fn quarantine_corrupt_files() {
+        let dir = TempDir::new();
+        let datapath = dir.path();
+        std::fs::create_dir_all(datapath).unwrap();
+        chat::Room { name: "good".to_string(), ..Default::default() }
+            .to_file(&roomlog::room_path(datapath, "good")).unwrap();
+        // a truncated protobuf and an unfinished atomic write
+        std::fs::write(roomlog::room_path(datapath, "bad"), [0x0a, 0x10, b'b']).unwrap();
+        std::fs::write(format!("{}/user_eve", datapath), [0xff; 4]).unwrap();
+        std::fs::write(format!("{}/{}room_good", datapath, common::TMP_PREFIX), [0x0a]).unwrap();
+        // a room whose name merely looks like a temporary file survives
+        chat::Room { name: "good.tmp".to_string(), ..Default::default() }
+            .to_file(&roomlog::room_path(datapath, "good.tmp")).unwrap();
+
+        let server = test_server(datapath);
+        let response = server.getrooms(authed("alice", chat::GetRoomsRequest {
+            client: Some(client("alice")),
+        })).await.unwrap().into_inner();
+        let mut names = room_names(&response);
+        names.sort();
+        assert_eq!(names, ["good", "good.tmp"]);
+        let quarantined = std::fs::read_dir(format!("{}/quarantine", datapath)).unwrap().count();
+        assert_eq!(quarantined, 2);
+        assert!(!std::path::Path::new(&roomlog::room_path(datapath, "bad")).exists());
+        assert!(!std::path::Path::new(&format!("{}/{}room_good", datapath, common::TMP_PREFIX)).exists());
+    }
+
+    #[tokio::test]
+    async fn legacy_room_file_scrubbed() {
+        let dir = TempDir::new();
+        let datapath = dir.path();
+        std::fs::create_dir_all(datapath).unwrap();
+        let legacy = chat::Room {
+            name: "old".to_string(),
+            messages: vec![chat::Message {
+                msg_type: chat::MessageType::Text as i32,
+                bytes: b"hi".to_vec(),
+                client: Some(client("bob")),
+                time: 0,
+                ..Default::default()
+            }],
+            manner: Some(client("bob")),
+            clients: vec![client("bob")],
+            history_visible: true,
+            ..Default::default()
+        };
+        let roomfile = format!("{}/room_old", datapath);
+        legacy.to_file(&roomfile).unwrap();
+
+        let server = test_server(datapath);
+        let response = server.join(join_req("bob", "old", None)).await.unwrap().into_inner();
+        assert_eq!(response.messages.len(), 1);
+        assert_no_credential(&response);
+
+        let response = server.getrooms(authed("bob", chat::GetRoomsRequest {
+            client: Some(client("bob")),
+        })).await.unwrap().into_inner();
+        assert_no_credential(&response);
+
+        // the file on disk is rewritten without the passwords as well
+        assert_no_credential(&chat::Room::from_file(&roomfile).unwrap());
+    }
 }
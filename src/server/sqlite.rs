@@ -0,0 +1,238 @@
+use std::sync::Mutex;
+use prost::Message;
+use rusqlite::{params, Connection};
+use crate::chat;
+use crate::common;
+use crate::server::storage::{Storage, StorageResult};
+
+// 数据目录下的数据库文件名
+pub const DB_FILE: &str = "chat.db";
+
+const SCHEMA: &str = "
+    CREATE TABLE IF NOT EXISTS rooms (
+        name TEXT PRIMARY KEY,
+        -- chat::Room without messages
+        data BLOB NOT NULL
+    );
+    CREATE TABLE IF NOT EXISTS users (
+        name TEXT PRIMARY KEY,
+        data BLOB NOT NULL
+    );
+    CREATE TABLE IF NOT EXISTS messages (
+        room TEXT NOT NULL,
+        idx INTEGER NOT NULL,
+        data BLOB NOT NULL,
+        PRIMARY KEY (room, idx)
+    );
+    -- rows that could not be decoded at startup
+    CREATE TABLE IF NOT EXISTS quarantine (
+        tbl TEXT NOT NULL,
+        name TEXT NOT NULL,
+        idx INTEGER,
+        data BLOB NOT NULL,
+        time INTEGER NOT NULL
+    );
+";
+
+// 房间设置、用户和信息分别存在三张表里，历史信息按(room, idx)索引
+pub struct SqliteStorage {
+    conn: Mutex<Connection>,
+}
+
+impl SqliteStorage {
+    pub fn open(datapath: &str) -> StorageResult<Self> {
+        std::fs::create_dir_all(datapath)?;
+        let conn = Connection::open(format!("{}/{}", datapath, DB_FILE))?;
+        conn.pragma_update(None, "journal_mode", "WAL")?;
+        conn.pragma_update(None, "synchronous", "FULL")?;
+        conn.execute_batch(SCHEMA)?;
+        Ok(SqliteStorage { conn: Mutex::new(conn) })
+    }
+}
+
+impl Storage for SqliteStorage {
+    fn load_rooms(&self) -> StorageResult<Vec<chat::Room>> {
+        let mut conn = self.conn.lock().unwrap();
+        let mut rooms = vec![];
+        let mut broken = vec![];
+        {
+            let mut stmt = conn.prepare("SELECT name, data FROM rooms ORDER BY name")?;
+            let mut message_stmt = conn.prepare("SELECT data FROM messages WHERE room = ?1 ORDER BY idx")?;
+            let rows = stmt.query_map([], |row| Ok((row.get::<_, String>(0)?, row.get::<_, Vec<u8>>(1)?)))?;
+            for row in rows {
+                let (name, data) = row?;
+                let mut decoded = chat::Room::decode(&data[..]).map_err(|e| e.to_string());
+                if let Ok(room) = decoded.as_mut() {
+                    for data in message_stmt.query_map([&name], |row| row.get::<_, Vec<u8>>(0))? {
+                        match chat::Message::decode(&data?[..]) {
+                            Ok(message) => room.messages.push(message),
+                            Err(e) => {
+                                decoded = Err(format!("message {}: {}", room.messages.len(), e));
+                                break;
+                            },
+                        }
+                    }
+                }
+                match decoded {
+                    Ok(room) => rooms.push(room),
+                    Err(e) => broken.push((name, e)),
+                }
+            }
+        }
+        // 和文件后端一样，读不出的房间连同它的信息移到quarantine表，不影响启动
+        for (name, err) in broken {
+            let tx = conn.transaction()?;
+            let now = common::now_milli_seconds() as i64;
+            tx.execute("INSERT INTO quarantine (tbl, name, idx, data, time)
+                SELECT 'rooms', name, NULL, data, ?2 FROM rooms WHERE name = ?1", params![name, now])?;
+            tx.execute("INSERT INTO quarantine (tbl, name, idx, data, time)
+                SELECT 'messages', room, idx, data, ?2 FROM messages WHERE room = ?1", params![name, now])?;
+            tx.execute("DELETE FROM rooms WHERE name = ?1", [&name])?;
+            tx.execute("DELETE FROM messages WHERE room = ?1", [&name])?;
+            tx.commit()?;
+            log::error!("quarantine unreadable room [{}]: {}", name, err);
+        }
+        Ok(rooms)
+    }
+
+    fn load_users(&self) -> StorageResult<Vec<chat::User>> {
+        let mut conn = self.conn.lock().unwrap();
+        let mut users = vec![];
+        let mut broken = vec![];
+        {
+            let mut stmt = conn.prepare("SELECT name, data FROM users ORDER BY name")?;
+            for row in stmt.query_map([], |row| Ok((row.get::<_, String>(0)?, row.get::<_, Vec<u8>>(1)?)))? {
+                let (name, data) = row?;
+                match chat::User::decode(&data[..]) {
+                    Ok(user) => users.push(user),
+                    Err(e) => broken.push((name, e)),
+                }
+            }
+        }
+        for (name, err) in broken {
+            let tx = conn.transaction()?;
+            tx.execute("INSERT INTO quarantine (tbl, name, idx, data, time)
+                SELECT 'users', name, NULL, data, ?2 FROM users WHERE name = ?1",
+                params![name, common::now_milli_seconds() as i64])?;
+            tx.execute("DELETE FROM users WHERE name = ?1", [&name])?;
+            tx.commit()?;
+            log::error!("quarantine unreadable user [{}]: {}", name, err);
+        }
+        Ok(users)
+    }
+
+    fn save_room(&self, room: &chat::Room) -> StorageResult<()> {
+        let settings = chat::Room {
+            messages: vec![],
+            ..room.clone()
+        };
+        self.conn.lock().unwrap().execute(
+            "INSERT OR REPLACE INTO rooms (name, data) VALUES (?1, ?2)",
+            params![room.name, settings.encode_to_vec()])?;
+        Ok(())
+    }
+
+    // 信息已经逐条写入，没有日志需要压缩
+    fn compact_room(&self, room: &chat::Room) -> StorageResult<()> {
+        self.save_room(room)
+    }
+
+    fn save_user(&self, user: &chat::User) -> StorageResult<()> {
+        self.conn.lock().unwrap().execute(
+            "INSERT OR REPLACE INTO users (name, data) VALUES (?1, ?2)",
+            params![user.name, user.encode_to_vec()])?;
+        Ok(())
+    }
+
+    fn rename_room(&self, oldname: &str, room: &chat::Room) -> StorageResult<()> {
+        let settings = chat::Room {
+            messages: vec![],
+            ..room.clone()
+        };
+        let mut conn = self.conn.lock().unwrap();
+        let tx = conn.transaction()?;
+        tx.execute("DELETE FROM rooms WHERE name = ?1", [oldname])?;
+        tx.execute("INSERT OR REPLACE INTO rooms (name, data) VALUES (?1, ?2)",
+            params![room.name, settings.encode_to_vec()])?;
+        tx.execute("UPDATE messages SET room = ?1 WHERE room = ?2", [&room.name, oldname])?;
+        tx.commit()?;
+        Ok(())
+    }
+
+    fn delete_room(&self, roomname: &str) -> StorageResult<()> {
+        let mut conn = self.conn.lock().unwrap();
+        let tx = conn.transaction()?;
+        tx.execute("DELETE FROM rooms WHERE name = ?1", [roomname])?;
+        tx.execute("DELETE FROM messages WHERE room = ?1", [roomname])?;
+        tx.commit()?;
+        Ok(())
+    }
+
+    fn append_message(&self, room: &chat::Room) -> StorageResult<()> {
+        self.update_message(room, room.messages.len() - 1)
+    }
+
+    fn update_message(&self, room: &chat::Room, index: usize) -> StorageResult<()> {
+        self.conn.lock().unwrap().execute(
+            "INSERT OR REPLACE INTO messages (room, idx, data) VALUES (?1, ?2, ?3)",
+            params![room.name, index as i64, room.messages[index].encode_to_vec()])?;
+        Ok(())
+    }
+
+    // seq == idx + 1，沿(room, idx)主键倒着取最后limit条
+    fn query_history(&self, room: &chat::Room, after_seq: u64, before_seq: u64, limit: usize) -> StorageResult<Vec<chat::Message>> {
+        let before_idx = if before_seq == 0 { i64::MAX } else { (before_seq - 1).min(i64::MAX as u64) as i64 };
+        let conn = self.conn.lock().unwrap();
+        let mut stmt = conn.prepare(
+            "SELECT data FROM messages WHERE room = ?1 AND idx >= ?2 AND idx < ?3 ORDER BY idx DESC LIMIT ?4")?;
+        let mut messages = vec![];
+        let params = params![room.name, after_seq.min(i64::MAX as u64) as i64, before_idx, limit.min(i64::MAX as usize) as i64];
+        for data in stmt.query_map(params, |row| row.get::<_, Vec<u8>>(0))? {
+            messages.push(chat::Message::decode(&data?[..])?);
+        }
+        messages.reverse();
+        Ok(messages)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::server::storage::tests::{storage_contract, TempDir};
+
+    #[test]
+    fn sqlite_storage() {
+        let dir = TempDir::new();
+        let datapath = dir.path();
+        storage_contract(|| SqliteStorage::open(datapath).unwrap());
+    }
+
+    #[test]
+    fn quarantine_undecodable_rows() {
+        let dir = TempDir::new();
+        let storage = SqliteStorage::open(dir.path()).unwrap();
+        let mut good = chat::Room { name: "good".to_string(), ..Default::default() };
+        storage.save_room(&good).unwrap();
+        good.messages.push(chat::Message { seq: 1, ..Default::default() });
+        storage.append_message(&good).unwrap();
+        let bad_message = chat::Room { name: "bad_message".to_string(), ..Default::default() };
+        storage.save_room(&bad_message).unwrap();
+        storage.save_user(&chat::User { name: "alice".to_string(), ..Default::default() }).unwrap();
+        {
+            let conn = storage.conn.lock().unwrap();
+            conn.execute("INSERT INTO rooms (name, data) VALUES ('bad', ?1)", [vec![0x0a, 0x10, b'b']]).unwrap();
+            conn.execute("INSERT INTO messages (room, idx, data) VALUES ('bad_message', 0, ?1)", [vec![0xff; 4]]).unwrap();
+            conn.execute("INSERT INTO users (name, data) VALUES ('eve', ?1)", [vec![0xff; 4]]).unwrap();
+        }
+
+        assert_eq!(storage.load_rooms().unwrap(), [good.clone()]);
+        let users = storage.load_users().unwrap();
+        assert_eq!(users.len(), 1);
+        assert_eq!(users[0].name, "alice");
+        let quarantined: i64 = storage.conn.lock().unwrap()
+            .query_row("SELECT COUNT(*) FROM quarantine", [], |row| row.get(0)).unwrap();
+        assert_eq!(quarantined, 4);
+        // the next start does not see them again
+        assert_eq!(storage.load_rooms().unwrap(), [good]);
+    }
+}
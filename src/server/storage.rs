@@ -0,0 +1,347 @@
+use std::sync::Mutex;
+use std::collections::HashMap;
+use crate::chat;
+use crate::common;
+use crate::server::roomlog;
+
+pub type StorageResult<T> = Result<T, Box<dyn std::error::Error>>;
+
+// 持久化后端。MyChatServer在内存中保存全部房间和用户，只在它们变化时调用这里
+pub trait Storage: Send + Sync + 'static {
+    // 启动时加载全部房间，包括其中的信息
+    fn load_rooms(&self) -> StorageResult<Vec<chat::Room>>;
+    fn load_users(&self) -> StorageResult<Vec<chat::User>>;
+    // 保存房间的成员和设置，房间的信息由append_message保存
+    fn save_room(&self, room: &chat::Room) -> StorageResult<()>;
+    // 把房间连同全部信息整个写一次，关闭时和清理旧数据后调用
+    fn compact_room(&self, room: &chat::Room) -> StorageResult<()>;
+    fn save_user(&self, user: &chat::User) -> StorageResult<()>;
+    // 房间改名为room.name，连同全部信息一起迁移
+    fn rename_room(&self, oldname: &str, room: &chat::Room) -> StorageResult<()>;
+    // 删除房间和它的全部信息
+    fn delete_room(&self, roomname: &str) -> StorageResult<()>;
+    // 保存房间刚刚追加的最后一条信息
+    fn append_message(&self, room: &chat::Room) -> StorageResult<()>;
+    // 保存房间中被修改的第index条信息
+    fn update_message(&self, room: &chat::Room, index: usize) -> StorageResult<()>;
+    // 房间中after_seq < seq < before_seq(为0时不限)的最后limit条信息，按seq升序
+    fn query_history(&self, room: &chat::Room, after_seq: u64, before_seq: u64, limit: usize) -> StorageResult<Vec<chat::Message>>;
+}
+
+// 每个房间一个room_<name>快照加一个log_<name>日志，日志记录快照之后的信息和设置，每个用户一个user_<name>文件
+pub struct FileStorage {
+    datapath: String,
+    // map roomname to the number of entries appended to its log since the last snapshot
+    logged: Mutex<HashMap<String, usize>>,
+}
+
+impl FileStorage {
+    pub fn open(datapath: &str) -> StorageResult<Self> {
+        std::fs::create_dir_all(datapath)?;
+        for entry in std::fs::read_dir(datapath)? {
+            let entry = entry?;
+            // left by a crash before the rename, the target file is still intact
+            if entry.file_name().to_str().unwrap_or_default().starts_with(common::TMP_PREFIX) {
+                log::warn!("remove unfinished write {:?}", entry.path());
+                std::fs::remove_file(entry.path())?;
+            }
+        }
+        Ok(FileStorage {
+            datapath: datapath.to_string(),
+            logged: Mutex::new(HashMap::new()),
+        })
+    }
+
+    // 数据目录中以prefix开头的文件，返回(路径, 文件名)
+    fn scan(&self, prefix: &str) -> StorageResult<Vec<(String, String)>> {
+        let mut files = vec![];
+        for entry in std::fs::read_dir(&self.datapath)? {
+            let entry = entry?;
+            let filename = entry.file_name().to_str().unwrap_or_default().to_string();
+            if filename.starts_with(prefix) && entry.file_type()?.is_file() {
+                files.push((entry.path().to_str().unwrap().to_string(), filename));
+            }
+        }
+        Ok(files)
+    }
+
+    // 把无法解析的数据文件移到quarantine目录，返回新的路径
+    fn quarantine(&self, pathstr: &str, filename: &str, err: Box<dyn std::error::Error>) -> StorageResult<String> {
+        let dir = format!("{}/quarantine", self.datapath);
+        std::fs::create_dir_all(&dir)?;
+        let target = format!("{}/{}.{}", dir, filename, common::now_milli_seconds());
+        std::fs::rename(pathstr, &target)?;
+        log::error!("quarantine {} to {}: {}", pathstr, target, err);
+        Ok(target)
+    }
+
+    fn report_quarantined(quarantined: &[String]) {
+        if !quarantined.is_empty() {
+            log::error!("{} unreadable data files were quarantined: {:?}", quarantined.len(), quarantined);
+        }
+    }
+
+    // 写入房间的完整快照，快照之后日志里的记录就不再需要了
+    fn write_snapshot(&self, room: &chat::Room) -> StorageResult<()> {
+        room.to_file(&roomlog::room_path(&self.datapath, &room.name))?;
+        roomlog::truncate(&roomlog::log_path(&self.datapath, &room.name))?;
+        self.logged.lock().unwrap().remove(&room.name);
+        Ok(())
+    }
+
+    // 日志又多了一条记录，足够长时压缩成快照
+    fn logged_entry(&self, room: &chat::Room) -> StorageResult<()> {
+        let mut logged = self.logged.lock().unwrap();
+        let count = logged.entry(room.name.clone()).or_insert(0);
+        *count += 1;
+        if *count >= roomlog::COMPACT_EVERY {
+            drop(logged);
+            log::info!("compact log of room[{}]", room.name);
+            self.write_snapshot(room)?;
+        }
+        Ok(())
+    }
+}
+
+impl Storage for FileStorage {
+    fn load_rooms(&self) -> StorageResult<Vec<chat::Room>> {
+        let mut rooms = vec![];
+        let mut quarantined = vec![];
+        // log_<name> files are replayed together with their room
+        for (pathstr, filename) in self.scan("room_")? {
+            let mut room = match chat::Room::from_file(&pathstr) {
+                Ok(room) => room,
+                Err(e) => {
+                    quarantined.push(self.quarantine(&pathstr, &filename, e)?);
+                    continue;
+                },
+            };
+            let logpath = roomlog::log_path(&self.datapath, &room.name);
+            // compact on startup so the log starts empty
+            match roomlog::replay(&logpath, &mut room) {
+                Ok(0) => {},
+                Ok(replayed) => {
+                    log::info!("replay {} logged entries of room [{}]", replayed, room.name);
+                    room.to_file(&pathstr)?;
+                },
+                // keep what was replayed, the rest of the log waits in quarantine
+                Err(e) if e.is::<prost::DecodeError>() => {
+                    quarantined.push(self.quarantine(&logpath, &format!("log_{}", room.name), e)?);
+                    room.to_file(&pathstr)?;
+                },
+                Err(e) => return Err(e),
+            }
+            roomlog::truncate(&logpath)?;
+            rooms.push(room);
+        }
+        Self::report_quarantined(&quarantined);
+        Ok(rooms)
+    }
+
+    fn load_users(&self) -> StorageResult<Vec<chat::User>> {
+        let mut users = vec![];
+        let mut quarantined = vec![];
+        for (pathstr, filename) in self.scan("user_")? {
+            match chat::User::from_file(&pathstr) {
+                Ok(user) => users.push(user),
+                Err(e) => quarantined.push(self.quarantine(&pathstr, &filename, e)?),
+            }
+        }
+        Self::report_quarantined(&quarantined);
+        Ok(users)
+    }
+
+    // 成员和设置作为一条记录追加到日志，只有新房间才写快照
+    fn save_room(&self, room: &chat::Room) -> StorageResult<()> {
+        if !std::path::Path::new(&roomlog::room_path(&self.datapath, &room.name)).exists() {
+            return self.write_snapshot(room);
+        }
+        roomlog::append_settings(&roomlog::log_path(&self.datapath, &room.name), room)?;
+        self.logged_entry(room)
+    }
+
+    fn compact_room(&self, room: &chat::Room) -> StorageResult<()> {
+        self.write_snapshot(room)
+    }
+
+    fn save_user(&self, user: &chat::User) -> StorageResult<()> {
+        user.to_file(&format!("{}/user_{}", self.datapath, user.name))
+    }
+
+    // 先写新名字的快照再删旧文件，崩溃时最多留下一个重复的房间
+    fn rename_room(&self, oldname: &str, room: &chat::Room) -> StorageResult<()> {
+        self.write_snapshot(room)?;
+        self.delete_room(oldname)
+    }
+
+    fn delete_room(&self, roomname: &str) -> StorageResult<()> {
+        for path in [roomlog::room_path(&self.datapath, roomname), roomlog::log_path(&self.datapath, roomname)] {
+            match std::fs::remove_file(&path) {
+                Err(e) if e.kind() != std::io::ErrorKind::NotFound => return Err(e.into()),
+                _ => {},
+            }
+        }
+        self.logged.lock().unwrap().remove(roomname);
+        Ok(())
+    }
+
+    fn append_message(&self, room: &chat::Room) -> StorageResult<()> {
+        self.update_message(room, room.messages.len() - 1)
+    }
+
+    fn update_message(&self, room: &chat::Room, index: usize) -> StorageResult<()> {
+        roomlog::append(&roomlog::log_path(&self.datapath, &room.name), index, &room.messages[index])?;
+        self.logged_entry(room)
+    }
+
+    // 文件后端没有索引，内存中的房间就是完整的历史
+    fn query_history(&self, room: &chat::Room, after_seq: u64, before_seq: u64, limit: usize) -> StorageResult<Vec<chat::Message>> {
+        let from = room.messages.partition_point(|m| m.seq <= after_seq);
+        let end = if before_seq == 0 { room.messages.len() } else { room.messages.partition_point(|m| m.seq < before_seq) };
+        let end = end.max(from);
+        let start = from.max(end.saturating_sub(limit));
+        Ok(room.messages[start..end].to_vec())
+    }
+}
+
+#[cfg(test)]
+pub mod tests {
+    use super::*;
+
+    // 测试用的数据目录，drop时删除，断言失败时也不会留下
+    pub struct TempDir(String);
+
+    impl TempDir {
+        pub fn new() -> Self {
+            let dir = std::env::temp_dir().join(format!("chatserver_test_{}", rand::random::<u64>()));
+            TempDir(dir.to_str().unwrap().to_string())
+        }
+
+        pub fn path(&self) -> &str {
+            &self.0
+        }
+    }
+
+    impl Default for TempDir {
+        fn default() -> Self {
+            Self::new()
+        }
+    }
+
+    impl Drop for TempDir {
+        fn drop(&mut self) {
+            let _ = std::fs::remove_dir_all(&self.0);
+        }
+    }
+
+    fn message(text: &str, seq: u64) -> chat::Message {
+        chat::Message {
+            bytes: text.as_bytes().to_vec(),
+            seq,
+            ..Default::default()
+        }
+    }
+
+    // 所有后端都应满足的行为
+    pub fn storage_contract<S: Storage>(open: impl Fn() -> S) {
+        let storage = open();
+        let mut room = chat::Room {
+            name: "r1".to_string(),
+            history_visible: true,
+            ..Default::default()
+        };
+        storage.save_room(&room).unwrap();
+        for text in ["a", "b", "c"] {
+            room.messages.push(message(text, room.last_seq() + 1));
+            storage.append_message(&room).unwrap();
+        }
+        room.password = Some("changed".to_string());
+        storage.save_room(&room).unwrap();
+        room.messages.push(message("d", 4));
+        storage.append_message(&room).unwrap();
+        room.messages[1] = message("B", 2);
+        storage.update_message(&room, 1).unwrap();
+        storage.save_user(&chat::User { name: "bedroom".to_string(), ..Default::default() }).unwrap();
+
+        let mut doomed = chat::Room { name: "r2".to_string(), ..Default::default() };
+        doomed.messages.push(message("x", 1));
+        storage.save_room(&doomed).unwrap();
+        storage.append_message(&doomed).unwrap();
+        storage.delete_room("r2").unwrap();
+        room.name = "r3".to_string();
+        storage.rename_room("r1", &room).unwrap();
+        storage.compact_room(&room).unwrap();
+        room.messages.push(message("e", 5));
+        storage.append_message(&room).unwrap();
+        drop(storage);
+
+        let storage = open();
+        let rooms = storage.load_rooms().unwrap();
+        assert_eq!(rooms, [room.clone()]);
+        let history = storage.query_history(&rooms[0], 1, 5, 2).unwrap();
+        assert_eq!(history, [message("c", 3), message("d", 4)]);
+        let history = storage.query_history(&rooms[0], 1, 0, 10).unwrap();
+        assert_eq!(history, room.messages[1..]);
+        assert!(storage.query_history(&rooms[0], 3, 4, 10).unwrap().is_empty());
+        assert!(storage.query_history(&rooms[0], 4, 2, 10).unwrap().is_empty());
+        let users = storage.load_users().unwrap();
+        assert_eq!(users.len(), 1);
+        assert_eq!(users[0].name, "bedroom");
+    }
+
+    #[test]
+    fn file_storage() {
+        let dir = TempDir::new();
+        let datapath = dir.path();
+        storage_contract(|| FileStorage::open(datapath).unwrap());
+    }
+
+    #[test]
+    fn settings_are_logged_not_snapshotted() {
+        let dir = TempDir::new();
+        let storage = FileStorage::open(dir.path()).unwrap();
+        let mut room = chat::Room { name: "r1".to_string(), ..Default::default() };
+        storage.save_room(&room).unwrap();
+        room.messages.push(message("a", 1));
+        storage.append_message(&room).unwrap();
+        let snapshot = roomlog::room_path(dir.path(), "r1");
+        let before = std::fs::read(&snapshot).unwrap();
+        room.history_visible = true;
+        room.roles.insert("bob".to_string(), chat::Role::Moderator as i32);
+        storage.save_room(&room).unwrap();
+        assert_eq!(std::fs::read(&snapshot).unwrap(), before);
+        drop(storage);
+
+        let storage = FileStorage::open(dir.path()).unwrap();
+        assert_eq!(storage.load_rooms().unwrap(), [room]);
+    }
+
+    #[test]
+    fn corrupt_log_is_quarantined_not_truncated() {
+        let dir = TempDir::new();
+        let storage = FileStorage::open(dir.path()).unwrap();
+        let mut room = chat::Room { name: "r1".to_string(), ..Default::default() };
+        storage.save_room(&room).unwrap();
+        room.messages.push(message("a", 1));
+        storage.append_message(&room).unwrap();
+        let logpath = roomlog::log_path(dir.path(), "r1");
+        // a complete but undecodable record in the middle of the log
+        let mut log = std::fs::read(&logpath).unwrap();
+        log.extend([0x02, 0xff, 0xff]);
+        std::fs::write(&logpath, &log).unwrap();
+        room.messages.push(message("b", 2));
+        storage.append_message(&room).unwrap();
+        let corrupt = std::fs::read(&logpath).unwrap();
+        drop(storage);
+
+        let storage = FileStorage::open(dir.path()).unwrap();
+        let rooms = storage.load_rooms().unwrap();
+        assert_eq!(rooms[0].messages, [message("a", 1)]);
+        assert!(!std::path::Path::new(&logpath).exists());
+        let quarantined: Vec<_> = std::fs::read_dir(format!("{}/quarantine", dir.path())).unwrap()
+            .map(|entry| entry.unwrap().path())
+            .collect();
+        assert_eq!(quarantined.len(), 1);
+        assert_eq!(std::fs::read(&quarantined[0]).unwrap(), corrupt);
+    }
+}
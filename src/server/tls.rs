@@ -0,0 +1,179 @@
+use tonic::{Request, Status};
+use tonic::transport::{Certificate, Identity, ServerTlsConfig};
+use crate::common;
+use crate::server::config::TlsConfig;
+
+// 配置了client_ca时要求客户端出示由它签发的证书
+pub fn server_tls_config(tls: &TlsConfig) -> Result<ServerTlsConfig, Box<dyn std::error::Error>> {
+    let read = |path: &str| std::fs::read(path).map_err(|e| format!("read {}: {}", path, e));
+    let mut config = ServerTlsConfig::new().identity(Identity::from_pem(read(&tls.cert)?, read(&tls.key)?));
+    if let Some(client_ca) = &tls.client_ca {
+        config = config.client_ca_root(Certificate::from_pem(read(client_ca)?));
+    }
+    Ok(config)
+}
+
+// 客户端证书绑定的设备序列号，没有客户端证书时返回None
+pub fn peer_device<T>(request: &Request<T>) -> Result<Option<String>, Status> {
+    let certs = match request.peer_certs() {
+        Some(certs) => certs,
+        None => return Ok(None),
+    };
+    certs.first()
+        .and_then(|cert| common::certificate_common_name(cert.get_ref()))
+        .map(Some)
+        .ok_or_else(|| Status::unauthenticated("client certificate has no common name"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rcgen::{BasicConstraints, CertificateParams, DnType, IsCa, KeyPair};
+    use tokio_stream::wrappers::TcpListenerStream;
+    use crate::chat;
+    use crate::chat::chat_server::ChatServer;
+    use crate::client::clib;
+    use crate::server::config::Config;
+    use crate::server::session::SessionInterceptor;
+    use crate::server::slib::MyChatServer;
+    use crate::server::storage::FileStorage;
+    use crate::server::storage::tests::TempDir;
+
+    struct TestCa {
+        cert: rcgen::Certificate,
+        key: KeyPair,
+    }
+
+    impl TestCa {
+        fn new() -> Self {
+            let key = KeyPair::generate().unwrap();
+            let mut params = CertificateParams::new(Vec::<String>::new()).unwrap();
+            params.is_ca = IsCa::Ca(BasicConstraints::Unconstrained);
+            params.distinguished_name.push(DnType::CommonName, "chatserver test ca");
+            TestCa { cert: params.self_signed(&key).unwrap(), key }
+        }
+
+        // 签发一张证书，把证书和私钥写到dir下，返回它们的路径
+        fn issue(&self, dir: &str, common_name: &str) -> (String, String) {
+            let key = KeyPair::generate().unwrap();
+            let mut params = CertificateParams::new(vec!["localhost".to_string()]).unwrap();
+            params.distinguished_name.push(DnType::CommonName, common_name);
+            let cert = params.signed_by(&key, &self.cert, &self.key).unwrap();
+            let certpath = format!("{}/{}.pem", dir, common_name);
+            let keypath = format!("{}/{}.key", dir, common_name);
+            std::fs::write(&certpath, cert.pem()).unwrap();
+            std::fs::write(&keypath, key.serialize_pem()).unwrap();
+            (certpath, keypath)
+        }
+    }
+
+    async fn start_server(datapath: &str, tls: TlsConfig) -> String {
+        let config = Config {
+            datapath: datapath.to_string(),
+            tls: Some(tls.clone()),
+            ..Default::default()
+        };
+        let server = MyChatServer::new(config, FileStorage::open(datapath).unwrap());
+        server.init().unwrap();
+        let interceptor = SessionInterceptor::new(server.sessions.clone());
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap().to_string();
+        tokio::spawn(tonic::transport::Server::builder()
+            .tls_config(server_tls_config(&tls).unwrap()).unwrap()
+            .add_service(ChatServer::with_interceptor(server, interceptor))
+            .serve_with_incoming(TcpListenerStream::new(listener)));
+        addr
+    }
+
+    fn client(name: &str, device: &str) -> chat::Client {
+        chat::Client {
+            user: Some(chat::User { name: name.to_string(), ..Default::default() }),
+            device: Some(chat::Device { serial_number: device.to_string() }),
+        }
+    }
+
+    fn signup_req(name: &str, device: &str) -> chat::UserSignupRequest {
+        chat::UserSignupRequest {
+            client: Some(client(name, device)),
+            password: "pw".to_string(),
+        }
+    }
+
+    fn getrooms_req(token: &str) -> tonic::Request<chat::GetRoomsRequest> {
+        let mut request = tonic::Request::new(chat::GetRoomsRequest { client: Some(client("alice", "")) });
+        let value = format!("{}{}", common::BEARER_PREFIX, token);
+        request.metadata_mut().insert(common::AUTH_METADATA, value.parse().unwrap());
+        request
+    }
+
+    #[tokio::test]
+    async fn server_tls() {
+        let dir = TempDir::new();
+        let datapath = dir.path();
+        std::fs::create_dir_all(datapath).unwrap();
+        let ca = TestCa::new();
+        let (cert, key) = ca.issue(datapath, "server");
+        let addr = start_server(datapath, TlsConfig { cert, key, client_ca: None }).await;
+        let capath = format!("{}/ca.pem", datapath);
+        std::fs::write(&capath, ca.cert.pem()).unwrap();
+
+        let options = clib::TlsOptions {
+            ca: capath,
+            domain: Some("localhost".to_string()),
+            ..Default::default()
+        };
+        let mut channel = clib::connect(&addr, Some(&options)).await.unwrap();
+        let response = channel.signup(signup_req("alice", "")).await.unwrap().into_inner();
+        assert!(!response.token.is_empty());
+        channel.getrooms(getrooms_req(&response.token)).await.unwrap();
+        // a plaintext client cannot talk to it
+        let rejected = match clib::connect(&addr, None).await {
+            Ok(mut channel) => channel.signup(signup_req("bob", "")).await.is_err(),
+            Err(_) => true,
+        };
+        assert!(rejected);
+    }
+
+    #[tokio::test]
+    async fn mutual_tls_binds_device() {
+        let dir = TempDir::new();
+        let datapath = dir.path();
+        std::fs::create_dir_all(datapath).unwrap();
+        let ca = TestCa::new();
+        let capath = format!("{}/ca.pem", datapath);
+        std::fs::write(&capath, ca.cert.pem()).unwrap();
+        let (cert, key) = ca.issue(datapath, "server");
+        let tls = TlsConfig { cert, key, client_ca: Some(capath.clone()) };
+        let addr = start_server(datapath, tls).await;
+
+        let options = |device: &str| {
+            let (cert, key) = ca.issue(datapath, device);
+            assert_eq!(common::pem_common_name(&std::fs::read(&cert).unwrap()).unwrap(), device);
+            clib::TlsOptions {
+                ca: capath.clone(),
+                cert: Some(cert),
+                key: Some(key),
+                domain: Some("localhost".to_string()),
+            }
+        };
+        let mut dev1 = clib::connect(&addr, Some(&options("dev-1"))).await.unwrap();
+        let mut dev2 = clib::connect(&addr, Some(&options("dev-2"))).await.unwrap();
+
+        // the claimed serial number must match the certificate
+        let status = dev1.signup(signup_req("alice", "dev-2")).await.unwrap_err();
+        assert_eq!(status.code(), tonic::Code::PermissionDenied);
+        let token = dev1.signup(signup_req("alice", "dev-1")).await.unwrap().into_inner().token;
+        dev1.getrooms(getrooms_req(&token)).await.unwrap();
+        // the token is useless on another device
+        let status = dev2.getrooms(getrooms_req(&token)).await.unwrap_err();
+        assert_eq!(status.code(), tonic::Code::Unauthenticated);
+
+        // no client certificate, no service
+        let anonymous = clib::TlsOptions { cert: None, key: None, ..options("unused") };
+        let rejected = match clib::connect(&addr, Some(&anonymous)).await {
+            Ok(mut channel) => channel.signup(signup_req("bob", "")).await.is_err(),
+            Err(_) => true,
+        };
+        assert!(rejected);
+    }
+}